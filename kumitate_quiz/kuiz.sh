@@ -163,17 +163,29 @@ if printf '%s\n' "$query" | grep -q '^stats'; then
     exit 0
 fi
 
+# The bot restarted.  A pending timer survives the restart and asks
+# the next question.  Without one, repeat the open question.
+if [[ $query = '--bot-restarted' ]]; then
+    if [[ ! -s $timer_file && -s $question_file ]]; then
+        split_lines "$(cat "$question_file")"
+        printf_ 'Please choose [1-4]: %s (1: %s 2: %s 3: %s 4: %s).' \
+                "$question" "${choices_arr[0]}" "${choices_arr[1]}" "${choices_arr[2]}" "${choices_arr[3]}"
+    fi
+    exit 0
+fi
+
 # Handle the timer.
 if [[ -s $timer_file ]]; then
-    if [[ ! $(find "$timer_file" -cmin 1) ]]; then
+    if [[ $(cat "$timer_file") = "$query" ]]; then
+        rm "$timer_file"
+        # The timer expired, maybe late because the bot restarted.
+        # Ask next question.
+        ask_question "$(tail -n 1 "$question_file")"
+        exit 0
+    elif [[ ! $(find "$timer_file" -cmin 1) ]]; then
         rm "$timer_file"
     else
         # The timer is running, so ignore answers.
-        if [[ $(cat "$timer_file") = "$query" ]]; then
-            rm "$timer_file"
-            # The timer expired. Ask next question.
-            ask_question "$(tail -n 1 "$question_file")"
-        fi
         exit 0
     fi
 fi
@@ -7,8 +7,10 @@
 # Japanese tools.
 # 
 
-from irc.bot import SingleServerIRCBot
+from irc.bot import SingleServerIRCBot, ExponentialBackoff
+from irc.connection import Factory
 from irc.client import ip_numstr_to_quad, ip_quad_to_numstr
+from irc.modes import parse_user_modes, parse_channel_modes
 import gettext
 import string
 import random
@@ -17,8 +19,73 @@ import time
 import io
 import traceback
 import locale
-_ = gettext.gettext
+import collections
+import re
+import signal
+import json
+import datetime
+import argparse
+import unicodedata
+import socket
+import ssl
+import threading
+import queue
+import html
+import ipaddress
+import urllib.parse
+import urllib.request
+import http.client
+import contextlib
+import functools
 
+# Language of the current reply per thread, None for the process
+# locale.  See use_language().
+language_state = threading.local()
+
+def current_language():
+    return getattr(language_state, 'language', None)
+
+def _(message):
+    if current_language() is None:
+        return gettext.gettext(message)
+    return translation(current_language()).gettext(message)
+
+def ngettext(singular, plural, n):
+    if current_language() is None:
+        return gettext.ngettext(singular, plural, n)
+    return translation(current_language()).ngettext(singular, plural, n)
+
+@functools.lru_cache()
+def translation(language):
+    return gettext.translation('japanese_tools',
+                               gettext.bindtextdomain('japanese_tools'),
+                               languages=[language], fallback=True)
+
+@contextlib.contextmanager
+def use_language(language):
+    """Makes _() translate to language within the with block."""
+    previous = current_language()
+    language_state.language = language
+    try:
+        yield
+    finally:
+        language_state.language = previous
+
+# Each script is (command or list of commands, path[, options]).
+# Options:
+#   'input_mode': 'arg' (default) passes the text after the command as
+#                 the first argument, 'stdin-raw' writes it unchanged to
+#                 the script's stdin and passes no argument.
+#   'normalize':  'nfc' converts the argument to Unicode normal form
+#                 C, 'none' passes it on as it was sent.  The default
+#                 is 'none' for 'stdin-raw' and 'nfc' otherwise.
+#   'stderr_to_channel': True appends the script's stderr to its
+#                 output.  By default stderr only goes to the log.
+#   'max_concurrent': runs of the script at the same time, see
+#                 max_concurrent_runs.
+#   'overflow':   'truncate' (default) drops messages beyond
+#                 max_channel_lines, 'pm' sends them privately to the
+#                 user who ran the command.
 scripts = [('cdecl', '../cdecl/c.sh'),
            ('c++decl', '../cdecl/c++.sh'),
            ('rtk', '../rtk/rtk.sh'),
@@ -32,38 +99,563 @@ scripts = [('cdecl', '../cdecl/c.sh'),
            ('gt', '../google_translate/gt.sh'),
            ('define', '../google_dictionary/gd.py'),
            ('audio', '../audio/find_audio.sh'),
-           ('quiz', '../reading_quiz/quiz.sh'),
-           ('kuiz', '../kumitate_quiz/kuiz.sh'),
+           # The quizzes share their statistics between channels.
+           ('quiz', '../reading_quiz/quiz.sh', { 'max_concurrent': 1 }),
+           ('kuiz', '../kumitate_quiz/kuiz.sh', { 'max_concurrent': 1 }),
            ('calc', '../mueval/run.sh'),
            ('type', '../mueval/type.sh'),
-           ('utf', '../compare_encoding/compare_encoding.sh'),
+           ('utf', '../compare_encoding/compare_encoding.sh',
+            { 'normalize': 'none' }),
            ('count', '../google_count/google_count.sh'),
            ('lhc', '../lhc/lhc_info.sh')
            ]
 
-def run_script(path, argument, irc_source_target, ignore_errors=False):
+# Scripts learn through these environment variables who called them.
+# Other frontends reusing the scripts should set them too.
+# DMB_FRONTEND is the name of the frontend, DMB_PROTOCOL_VERSION the
+# version of this set of variables.
+frontend_name = 'irc'
+protocol_version = '1'
+
+# Answer to CTCP VERSION.
+ctcp_version = 'Japanese-Tools simple_bot'
+
+# Language of the replies per channel, e.g. { '#channel': 'de' }.
+# Scripts get it in LANGUAGE.  Other targets use the process locale.
+channel_languages = {}
+
+# Commands that do nothing in a channel, e.g. { '#channel': ['calc'] }.
+disabled_commands = {}
+# Nicks whose messages are ignored, except for admin commands.  The
+# admin commands ignore and unignore change this at runtime.
+ignored_nicks = []
+
+# Nicks of other bots.  Messages from bots never trigger commands so
+# that bots cannot talk to each other in an endless loop.
+known_bots = []
+
+# When users join, wait this many seconds for more joins and then
+# send one WHO for the whole channel.  A healing netsplit rejoins many
+# users at once.
+who_delay = 10
+# Messages per second we send at most, to avoid Excess Flood.
+send_rate_limit = 2
+# User modes the bot sets on itself after connecting.
+bot_user_modes = '+B'
+# User modes only the server can give, so there is no point in
+# requesting them after a reconnect.
+server_user_modes = 'oOrz'
+# Nick of the channel service to ask for operator status again after
+# a reconnect, e.g. 'ChanServ'.  None disables this.
+chanserv = None
+
+# The word of the day is picked from this file.  Each category is
+# chosen with the relative weight given here, 1 if not listed.
+word_of_the_day_pool = 'word_of_the_day_pool.json'
+word_of_the_day_weights = {}
+# Words are not repeated within this many days.
+word_of_the_day_min_days = 60
+
+# After losing the connection, wait at least this many seconds before
+# reconnecting, doubling the wait up to reconnect_max_interval.
+reconnect_min_interval = 10
+reconnect_max_interval = 5 * 60
+
+# Scripts running longer than this many seconds are killed.
+script_timeout = 30
+# Number of runs of the same script at the same time, unless the
+# script has the option 'max_concurrent'.  Runs of a script for the
+# same channel or nick always wait for each other.
+max_concurrent_runs = 2
+# Runs of a script waiting for their turn.  Commands beyond this get
+# a busy reply.
+max_queued_runs = 10
+
+# Seconds to wait for a running script when shutting down.
+script_shutdown_timeout = 5
+# Seconds to spend at most on sending output when shutting down.
+# Messages after that are dropped.
+shutdown_output_timeout = 3
+
+# Notices from NickServ asking us to identify and confirming that we
+# did.  Used to identify again after services were restarted.
+nickserv_identify_patterns = ['This nickname is registered']
+nickserv_success_patterns = ['You are now identified']
+
+# The magic key for admin commands is stored in this file.
+magic_key_file = 'magic_key.txt'
+
+# Exit if the main loop makes no progress for this many seconds so
+# that systemd can restart the bot.  None disables the watchdog.
+watchdog_timeout = 120
+watchdog_exit_code = 3
+
+# Scripts failing this many times in a row are disabled...
+max_consecutive_failures = 5
+# ...for this many seconds.
+disabled_script_cooldown = 30 * 60
+
+# Limits for "/timer <seconds> <argument>" lines in script output.
+max_timer_delay = 24 * 60 * 60
+max_timer_argument_length = 200
+
+# Reminders set with !remind may be at most this many seconds away.
+max_reminder_delay = 7 * 24 * 60 * 60
+# Number of pending reminders per user.
+max_reminders_per_user = 5
+# Timers from before a restart are dropped if we are not back in
+# their channel this many seconds after they were due.
+restored_timer_expiry = 60 * 60
+
+# Commands available with the magic key.
+admin_commands = ['die', 'join', 'part', 'raw', 'privmsg', 'trustbot',
+                  'latency', 'wotd', 'dump-traffic', 'ignore-moderation',
+                  'enable-script', 'ignore', 'unignore']
+
+# Length of an IRC line including CRLF if the server does not
+# announce LINELEN.
+default_line_length = 512
+# Text per message if LINELEN leaves less room than this, e.g. for a
+# very long channel name.  The server cuts off the end of such lines.
+min_message_length = 100
+
+# Maximum number of messages per reply.  Long lines are split into
+# several messages.
+max_channel_lines = 6
+# Maximum number of messages sent privately with 'overflow': 'pm'.
+max_overflow_lines = 20
+# Appended to the last message if the rest of a reply is dropped.
+truncation_marker = ' […]'
+
+# After an unclean restart or disconnect, tell the channels that
+# commands may have been lost.  Needs the state in bot_state_file.
+offline_notice = False
+bot_state_file = 'bot_state.json'
+# Post this at most once in this many seconds per channel.
+offline_notice_interval = 60 * 60
+
+# Log at most this many bytes of a script's stderr.
+max_logged_stderr = 1024
+
+# Channels where the bot posts the titles of pasted links.
+url_title_channels = []
+# Domains to fetch titles from, including their subdomains.  None
+# allows all domains not in url_title_denied_domains.
+url_title_allowed_domains = None
+url_title_denied_domains = []
+# Limits for fetching a page.
+url_title_max_bytes = 256 * 1024
+url_title_timeout = 5
+# Titles of at most this many links per message, and at most this
+# many pages fetched at the same time.
+url_title_max_links = 3
+url_title_max_fetches = 4
+# The title of the same link is posted at most once in this many
+# seconds.
+url_title_repeat = 10 * 60
+
+# Scripts started by run_script() that are still running.
+running_processes = set()
+
+def kill_process_group(process):
+    try:
+        os.killpg(process.pid, signal.SIGKILL)
+    except ProcessLookupError:
+        pass
+
+def run_script(path, argument, irc_source_target, ignore_errors=False,
+               extra_env={}, input_mode='arg', timeout=None):
+    """Returns the output of the script, its stderr and whether the
+    script could be run at all."""
     try:
-        env = os.environ
+        # Scripts run in several threads, don't share os.environ.
+        env = dict(os.environ)
         lang = env.get('LANG', 'en_US.utf8')
-        env.update({ 'DMB_SENDER'   : irc_source_target[0],
-                     'DMB_RECEIVER' : irc_source_target[1],
-                     'LANGUAGE'     : lang,
-                     'LANG'         : lang,
-                     'LC_ALL'       : lang,
-                     'IRC_PLUGIN'   : '1' })
-        output = subprocess.Popen(
-            [path, argument],
+        receiver_type = ('channel' if is_channel(irc_source_target[1])
+                         else 'private')
+        env.update({ 'DMB_SENDER'           : irc_source_target[0],
+                     'DMB_RECEIVER'         : irc_source_target[1],
+                     'DMB_RECEIVER_TYPE'    : receiver_type,
+                     'DMB_FRONTEND'         : frontend_name,
+                     'DMB_PROTOCOL_VERSION' : protocol_version,
+                     'LANGUAGE'             : lang,
+                     'LANG'                 : lang,
+                     'LC_ALL'               : lang,
+                     'IRC_PLUGIN'           : '1' })
+        env.pop('DMB_CHANNEL_EMPTY', None)
+        env.update(extra_env)
+        if input_mode == 'stdin-raw':
+            args = [path]
+            stdin_input = argument.encode('utf-8')
+        else:
+            args = [path, argument]
+            stdin_input = None
+        process = subprocess.Popen(
+            args,
+            stdin=subprocess.PIPE if stdin_input is not None else None,
             stdout=subprocess.PIPE,
             stderr=subprocess.PIPE,
             cwd=os.path.dirname(os.path.abspath(path)),
-            env=env
-            ).communicate()[0]
-        return output.decode('utf-8')
-    except:
+            env=env,
+            # Own process group so that a timeout kills the children too.
+            start_new_session=True
+            )
+        running_processes.add(process)
+        try:
+            output, errors = process.communicate(stdin_input, timeout=timeout)
+        except subprocess.TimeoutExpired:
+            kill_process_group(process)
+            errors = process.communicate()[1]
+            return (_('Command timed out.'),
+                    errors.decode('utf-8', 'replace'), False)
+        except KeyboardInterrupt:
+            # Only the main thread gets this, i.e. in bot.py
+            # run-script.  The bot stops its scripts in kill_scripts().
+            kill_process_group(process)
+            raise
+        finally:
+            running_processes.discard(process)
+        # 126 and 127 mean that the script or its interpreter could
+        # not be executed, negative values mean it was killed by a
+        # signal.  Other exit codes are the script's business.
+        success = 0 <= process.returncode < 126
+        return (output.decode('utf-8'),
+                errors.decode('utf-8', 'replace'), success)
+    except Exception:
         if ignore_errors:
-            return ''
+            return '', '', False
         else:
-            return _('An error occured.')
+            return _('An error occured.'), '', False
+
+def call_script(path, argument, irc_source_target, bot_nick, extra_env={},
+                timeout=None):
+    """Runs the script the way the bot does for a command.  Returns
+    the same as run_script()."""
+    options = script_options(path)
+    input_mode = options.get('input_mode', 'arg')
+    default_normalize = 'none' if input_mode == 'stdin-raw' else 'nfc'
+    if options.get('normalize', default_normalize) == 'nfc':
+        argument = unicodedata.normalize('NFC', argument)
+    extra_env = dict(extra_env, DMB_BOT_NICK=bot_nick)
+    language = channel_language(irc_source_target[1])
+    if language is not None:
+        extra_env['LANGUAGE'] = language
+    return run_script(
+        path, argument, irc_source_target, extra_env=extra_env,
+        input_mode=input_mode, timeout=timeout)
+
+# A script started by the bot.  target is where the reply goes, sender
+# gets overflowing lines (None for timers).
+ScriptJob = collections.namedtuple(
+    'ScriptJob', ['path', 'argument', 'source_target', 'target', 'sender',
+                  'from_timer', 'extra_env', 'language', 'bot_nick',
+                  'start_time'])
+
+def split_topic(topic, marker):
+    """Splits the topic into the part up to the marker, the word of
+    the day and the rest.  Returns None if the topic has no marker."""
+    if topic.find(marker) == -1:
+        return None
+    prefix, word = topic.split(marker, 1)
+    suffix = ''
+    if word.find(' ') != -1:
+        word, suffix = word.split(' ', 1)
+        suffix = ' ' + suffix
+    return prefix, word, suffix
+
+def compute_new_topic(old_topic, marker, new_word):
+    """Replaces the word of the day in old_topic.  Returns the new
+    topic and the old word."""
+    prefix, old_word, suffix = split_topic(old_topic, marker)
+    return '%s%s%s%s' % (prefix, marker, new_word, suffix), old_word
+
+def sd_notify(message):
+    """Sends a status message to systemd if it asked for one."""
+    address = os.environ.get('NOTIFY_SOCKET')
+    if not address:
+        return
+    if address.startswith('@'):
+        address = '\0' + address[1:]
+    try:
+        with socket.socket(socket.AF_UNIX, socket.SOCK_DGRAM) as s:
+            s.sendto(message.encode('utf-8'), address)
+    except OSError:
+        pass
+
+def redact_line(line, secrets):
+    """Removes passwords and other secrets from a raw IRC line."""
+    line = re.sub(r'(?i)^((?:\S+ )?PRIVMSG NickServ :\s*identify\s).*',
+                  r'\1***', line)
+    line = re.sub(r'(?i)^(PASS|OPER \S+) .*', r'\1 ***', line)
+    for secret in secrets:
+        if secret:
+            line = line.replace(secret, '***')
+    return line
+
+def is_channel(target):
+    return target[:1] in ('#', '&')
+
+rfc1459_lower_table = str.maketrans(string.ascii_uppercase + '[]\\~',
+                                    string.ascii_lowercase + '{}|^')
+
+def irc_lower(name):
+    """Case folding of nicks and channels as in RFC 1459.  Non-ASCII
+    characters are left alone, the server doesn't fold them either."""
+    return name.translate(rfc1459_lower_table)
+
+def is_valid_channel(name):
+    """Checks the channel name against the rules in RFC 2812.  The
+    length limit is in bytes, names may contain UTF-8."""
+    return (1 < len(name.encode('utf-8')) <= 50 and is_channel(name)
+            and not any(c in name for c in ' ,:\x07'))
+
+def parse_channels(arg):
+    """Parses a comma-separated list of channels, each optionally
+    followed by a key ("#channel:key").  Returns the channels, a dict
+    with the channel keys and the list of invalid entries."""
+    channels = []
+    keys = {}
+    invalid = []
+    for entry in arg.split(','):
+        entry = entry.strip()
+        channel, separator, key = entry.partition(':')
+        if (not is_valid_channel(channel)
+            or (separator and (not key or ' ' in key))):
+            invalid.append(entry)
+            continue
+        channels.append(channel)
+        if key:
+            keys[channel] = key
+    return channels, keys, invalid
+
+def is_pool_entry(entry):
+    """Checks the types in a word pool entry.  category and last_used
+    may be missing."""
+    return (type(entry) is dict and type(entry.get('word')) is str
+            and type(entry.get('category', '')) is str
+            and type(entry.get('last_used')) in (str, type(None)))
+
+def load_word_pool(path):
+    """Returns the list of words in the pool.  Each word is a dict
+    with the keys word, category and last_used (ISO date or None).
+    Broken entries are skipped.  Raises ValueError if the file is not
+    a JSON list."""
+    try:
+        with open(path, 'r') as f:
+            pool = json.load(f)
+    except FileNotFoundError:
+        return []
+    if type(pool) is not list:
+        raise ValueError(_('%s is not a list') % path)
+    return [entry for entry in pool if is_pool_entry(entry)]
+
+def save_word_pool(path, pool):
+    save_json(path, pool)
+
+def load_json(path, default):
+    try:
+        with open(path, 'r') as f:
+            return json.load(f)
+    except FileNotFoundError:
+        return default
+
+def timers_from_json(entries):
+    """Converts timers saved by save_timers() back to tuples.  Skips
+    broken entries and timers of scripts that no longer exist."""
+    timers = []
+    for entry in entries if type(entries) is list else []:
+        try:
+            deadline, script, argument, source_target, target = entry
+            timer = (float(deadline), script, str(argument),
+                     (str(source_target[0]), str(source_target[1])),
+                     str(target))
+        except (TypeError, ValueError, IndexError):
+            continue
+        if script is None or script in [s[1] for s in scripts]:
+            timers.append(timer)
+    return timers
+
+def timer_targets_from_json(entries):
+    """Converts the timer targets saved by save_timers() back to
+    [path, target].  Skips broken entries and scripts that no longer
+    exist."""
+    return [entry for entry in entries if type(entries) is list
+            and type(entry) is list and len(entry) == 2
+            and all(type(x) is str for x in entry)
+            and entry[0] in [s[1] for s in scripts]]
+
+def state_from_json(state):
+    """Checks the state read from bot_state_file, which may have been
+    edited by hand.  Drops values of the wrong type.  Raises
+    ValueError if the state is not a JSON object."""
+    if type(state) is not dict:
+        raise ValueError(_('not a JSON object'))
+    def number(x):
+        return type(x) in (int, float)
+    state = dict(state)
+    if 'last_seen' in state and not number(state['last_seen']):
+        del state['last_seen']
+    if 'offline_notices' in state:
+        notices = state['offline_notices']
+        state['offline_notices'] = {
+            channel: t for channel, t
+            in (notices.items() if type(notices) is dict else [])
+            if number(t) }
+    if 'ignored_nicks' in state:
+        nicks = state['ignored_nicks']
+        state['ignored_nicks'] = [
+            n for n in (nicks if type(nicks) is list else [])
+            if type(n) is str]
+    return state
+
+def save_json(path, data):
+    """Replaces the file.  After a crash we have either the old or
+    the new file, never a partial one."""
+    with open(path + '.tmp', 'w') as f:
+        json.dump(data, f, ensure_ascii=False, indent=1)
+        f.flush()
+        os.fsync(f.fileno())
+    os.rename(path + '.tmp', path)
+    # Make the rename itself durable.
+    fd = os.open(os.path.dirname(os.path.abspath(path)), os.O_RDONLY)
+    try:
+        os.fsync(fd)
+    finally:
+        os.close(fd)
+
+def is_word(line):
+    """Blank lines and lines starting with # are not words."""
+    line = line.strip()
+    return line != '' and not line.startswith('#')
+
+def import_word_list(path, category):
+    """Reads the old one-word-per-line format."""
+    try:
+        with open(path, 'r') as f:
+            return [{ 'word': line.strip(), 'category': category,
+                      'last_used': None }
+                    for line in f if is_word(line)]
+    except FileNotFoundError:
+        return []
+
+def pick_word_of_the_day(pool, weights, today, rng):
+    """Picks a category at random according to the weights and
+    returns the least recently used word in it.  Words used in the
+    last word_of_the_day_min_days days are skipped.  Returns None if
+    there is no such word."""
+    latest = (today - datetime.timedelta(days=word_of_the_day_min_days)).isoformat()
+    available = {}
+    for entry in pool:
+        if not is_word(entry['word']):
+            continue
+        last_used = entry.get('last_used')
+        if last_used is None or last_used <= latest:
+            available.setdefault(entry.get('category', 'default'),
+                                 []).append(entry)
+    categories = sorted(c for c in available if weights.get(c, 1) > 0)
+    if not categories:
+        return None
+    category = rng.choices(categories,
+                           [weights.get(c, 1) for c in categories])[0]
+    return min(available[category], key=lambda e: e.get('last_used') or '')
+
+def looks_like_bot(nick):
+    return irc_lower(nick).endswith('bot')
+
+def channel_language(target):
+    for channel, language in channel_languages.items():
+        if irc_lower(channel) == irc_lower(target):
+            return language
+    return None
+
+def command_disabled(command, target):
+    """Disabling one name of a script disables all its names."""
+    names = command
+    if type(names) is not list:
+        names = [command]
+        for s in scripts:
+            if command in (s[0] if type(s[0]) is list else [s[0]]):
+                names = s[0] if type(s[0]) is list else [s[0]]
+    for channel, commands in disabled_commands.items():
+        if irc_lower(channel) == irc_lower(target):
+            return any(n in commands for n in names)
+    return False
+
+def script_problem(path):
+    """Returns why path cannot be run or None if it looks fine."""
+    if not os.path.exists(path):
+        return _('does not exist')
+    if not os.path.isfile(path):
+        return _('is not a regular file')
+    if not os.access(path, os.X_OK):
+        return _('is not executable')
+    return None
+
+def script_options(path):
+    for s in scripts:
+        if s[1] == path:
+            return s[2] if len(s) > 2 else {}
+    return {}
+
+ServerAddress = collections.namedtuple('ServerAddress', ['host', 'port', 'tls'])
+
+def parse_server_address(address, force_tls=False):
+    """Parses "host[:port]" or "ircs://host[:port]".  TLS is used
+    with ircs://, on port 6697 and with force_tls.  Raises ValueError
+    for an invalid port."""
+    tls = True if force_tls else None
+    for scheme, scheme_tls in [('ircs://', True), ('irc://', False)]:
+        if address.startswith(scheme):
+            address = address[len(scheme):].rstrip('/')
+            tls = tls or scheme_tls
+    host, _sep, port = address.partition(':')
+    if port:
+        port = int(port)
+        if not 0 < port < 65536:
+            raise ValueError(port)
+    else:
+        port = 6697 if tls else 6667
+    if tls is None:
+        tls = port == 6697
+    return ServerAddress(host, port, tls)
+
+def find_script(command):
+    """Returns the path of the script for the given command or None."""
+    for s in scripts:
+        name = s[0]
+        if type(name) is not list:
+            name = [name]
+        if command in name:
+            return s[1]
+    return None
+
+def parse_timer_directive(line):
+    """Parses "/timer <seconds> <argument>".  Returns (seconds,
+    argument) or raises ValueError explaining what is wrong."""
+    parts = line.split(' ')
+    if len(parts) != 3:
+        raise ValueError(_('expected "/timer <seconds> <argument>"'))
+    if not re.fullmatch('[0-9]+', parts[1]):
+        raise ValueError(_('delay is not a number'))
+    delay = int(parts[1])
+    if delay > max_timer_delay:
+        raise ValueError(_('delay is longer than %d seconds')
+                         % max_timer_delay)
+    if not parts[2]:
+        raise ValueError(_('argument is empty'))
+    if len(parts[2]) > max_timer_argument_length:
+        raise ValueError(_('argument is longer than %d characters')
+                         % max_timer_argument_length)
+    return delay, parts[2]
+
+def message_budget(line_length, command, target, prefix_length):
+    """Returns the number of bytes left for the text in
+    "COMMAND target :text".  The server relays the line to others with
+    our prefix ":nick!user@host " in front, which must fit as well.
+    Returns at least min_message_length."""
+    overhead = len(('%s %s :\r\n' % (command, target)).encode('utf-8'))
+    return max(min_message_length,
+               line_length - overhead - (prefix_length + 2))
 
 def limit_length(s, max_bytes):
     """Limits the length of a unicode string after conversion to
@@ -73,36 +665,512 @@ def limit_length(s, max_bytes):
             return s[:limit]
     return ''
 
+def split_message(text, max_bytes, max_messages=None):
+    """Splits text into lines of at most max_bytes in utf-8, breaking
+    long lines at spaces if possible.  If there are more than
+    max_messages lines, the rest is replaced with truncation_marker."""
+    messages = []
+    for line in text.splitlines():
+        while len(line.encode('utf-8')) > max_bytes:
+            # Always make progress, even if not one character fits.
+            part = limit_length(line, max_bytes) or line[:1]
+            space = max(part.rfind(' '), part.rfind('　'))
+            if space > 0 and line[len(part)] not in ' 　':
+                part = part[:space]
+            messages.append(part.rstrip(' 　'))
+            line = line[len(part):].lstrip(' 　')
+            if not line:
+                break
+        else:
+            messages.append(line)
+    if max_messages is not None and len(messages) > max_messages:
+        messages = messages[:max_messages]
+        marker_bytes = len(truncation_marker.encode('utf-8'))
+        messages[-1] = limit_length(messages[-1], max_bytes - marker_bytes) \
+            .rstrip(' 　') + truncation_marker
+    return messages
+
+def find_urls(text):
+    # Punctuation at the end usually belongs to the sentence.
+    return [url.rstrip('.,;:!?)]\'') for url
+            in re.findall(r'https?://[^\s<>"]+', text)]
+
+def domain_matches(host, domains):
+    return any(host == d or host.endswith('.' + d) for d in domains)
+
+def url_allowed(url):
+    """Returns True if we may fetch url according to its scheme and
+    domain.  The address is checked when connecting, see
+    global_address()."""
+    try:
+        parts = urllib.parse.urlsplit(url)
+        host = (parts.hostname or '').lower()
+        # Raises ValueError for an invalid port.
+        parts.port
+    except ValueError:
+        return False
+    if parts.scheme not in ('http', 'https') or not host:
+        return False
+    if domain_matches(host, url_title_denied_domains):
+        return False
+    if (url_title_allowed_domains is not None
+            and not domain_matches(host, url_title_allowed_domains)):
+        return False
+    return True
+
+def global_address(host, port):
+    """Resolves host.  Returns its address if all its addresses are
+    public, None otherwise, so that nobody can make us probe our own
+    network."""
+    try:
+        addresses = socket.getaddrinfo(host, port, type=socket.SOCK_STREAM)
+    except (OSError, UnicodeError):
+        return None
+    if not addresses or not all(
+            ipaddress.ip_address(a[4][0].split('%')[0]).is_global
+            for a in addresses):
+        return None
+    return addresses[0][4][0]
+
+def pinned_socket(host, port, timeout):
+    """Connects to the address global_address() checked.  Resolving
+    the host again could give a different address."""
+    address = global_address(host, port)
+    if address is None:
+        raise OSError('Refusing to connect to %s' % host)
+    return socket.create_connection((address, port), timeout)
+
+class PinnedHTTPConnection(http.client.HTTPConnection):
+    def connect(self):
+        self.sock = pinned_socket(self.host, self.port, self.timeout)
+
+class PinnedHTTPSConnection(http.client.HTTPSConnection):
+    def connect(self):
+        sock = pinned_socket(self.host, self.port, self.timeout)
+        # The certificate must still match the host name.
+        self.sock = self._context.wrap_socket(sock, server_hostname=self.host)
+
+class PinnedHTTPHandler(urllib.request.HTTPHandler):
+    def http_open(self, req):
+        return self.do_open(PinnedHTTPConnection, req)
+
+class PinnedHTTPSHandler(urllib.request.HTTPSHandler):
+    def https_open(self, req):
+        return self.do_open(PinnedHTTPSConnection, req,
+                            context=self._context)
+
+class CheckedRedirectHandler(urllib.request.HTTPRedirectHandler):
+    """Follows only redirects to URLs we may fetch."""
+    def redirect_request(self, req, fp, code, msg, headers, newurl):
+        if not url_allowed(newurl):
+            return None
+        return super().redirect_request(req, fp, code, msg, headers, newurl)
+
+def extract_title(data, charset=None):
+    """Returns the title of an HTML page given as bytes or None."""
+    if charset is None:
+        match = re.search(rb'<meta[^>]+charset=["\']?([-\w]+)', data, re.I)
+        if match:
+            charset = match.group(1).decode('ascii')
+    try:
+        text = data.decode(charset or 'utf-8', 'replace')
+    except LookupError:
+        text = data.decode('utf-8', 'replace')
+    match = re.search(r'<title[^>]*>(.*?)</title>', text, re.I | re.S)
+    if match is None:
+        return None
+    title = ' '.join(html.unescape(match.group(1)).split())
+    if not title:
+        return None
+    if len(title.encode('utf-8')) > 300:
+        title = limit_length(title, 297) + '...'
+    return title
+
+def fetch_title(url):
+    """Returns the title of the page at url or None."""
+    if not url_allowed(url):
+        return None
+    # No proxy: we must connect to the address we checked.
+    opener = urllib.request.build_opener(
+        urllib.request.ProxyHandler({}), PinnedHTTPHandler,
+        PinnedHTTPSHandler, CheckedRedirectHandler)
+    request = urllib.request.Request(
+        url, headers={ 'User-Agent': 'Japanese-Tools simple_bot' })
+    try:
+        with opener.open(request, timeout=url_title_timeout) as response:
+            if response.headers.get_content_type() != 'text/html':
+                return None
+            data = response.read(url_title_max_bytes)
+            charset = response.headers.get_content_charset()
+    except Exception:
+        return None
+    return extract_title(data, charset)
+
+class Logger(object):
+    """Writes debug output to the console.  A message repeating
+    within a few seconds is only counted, not printed again."""
+    repeat_window = 10
+    max_recent = 8
+
+    def __init__(self, clock=time.time, out=sys.stdout):
+        self.clock = clock
+        self.out = out
+        # Maps normalized messages to [message, time of last output,
+        # number of suppressed repetitions].
+        self.recent = collections.OrderedDict()
+
+    def log(self, line):
+        now = self.clock()
+        # Ignore numbers like timestamps when comparing messages.
+        key = re.sub(r'[0-9]+', '0', line)
+        entry = self.recent.pop(key, None)
+        if entry is not None and now - entry[1] < self.repeat_window:
+            entry[2] += 1
+        else:
+            if entry is not None and entry[2] > 0:
+                self.write_repeated(entry)
+            self.write(line)
+            entry = [line, now, 0]
+        self.recent[key] = entry
+        while len(self.recent) > self.max_recent:
+            self.recent.popitem(last=False)
+
+    def flush(self):
+        """Prints pending repeat counts.  Should be called regularly."""
+        now = self.clock()
+        for entry in self.recent.values():
+            if entry[2] > 0 and now - entry[1] >= self.repeat_window:
+                self.write_repeated(entry)
+                entry[1] = now
+                entry[2] = 0
+
+    def write_repeated(self, entry):
+        self.write(_('Repeated %d times: %s') % (entry[2], entry[0]))
+
+    def write(self, line):
+        self.out.write(line + '\n')
+        self.out.flush()
+
+def format_duration(seconds):
+    """Formats seconds as days, hours and minutes."""
+    minutes = int(seconds) // 60
+    days, minutes = divmod(minutes, 24 * 60)
+    hours, minutes = divmod(minutes, 60)
+    parts = []
+    if days:
+        parts.append(ngettext('%d day', '%d days', days) % days)
+    if hours:
+        parts.append(ngettext('%d hour', '%d hours', hours) % hours)
+    if minutes or not parts:
+        parts.append(ngettext('%d minute', '%d minutes', minutes) % minutes)
+    return ', '.join(parts)
+
+def format_list(items, max_bytes):
+    """Joins items into one line of at most max_bytes, ending with
+    "+N more" if not all of them fit."""
+    for n in range(len(items), -1, -1):
+        line = ', '.join(items[:n])
+        if n < len(items):
+            line += (', ' if n else '') + _('+%d more') % (len(items) - n)
+        if len(line.encode('utf-8')) <= max_bytes:
+            return line
+    return ''
+
+duration_units = { 's': 1, '秒': 1, 'm': 60, '分': 60,
+                   'h': 60 * 60, '時間': 60 * 60,
+                   'd': 24 * 60 * 60, '日': 24 * 60 * 60 }
+
+def parse_duration(text):
+    """Parses durations like "25m", "1d12h" or "2時間". Returns the
+    number of seconds or None if text is not a duration."""
+    parts = re.findall(r'(\d+)(s|m|h|d|秒|分|時間|日)', text)
+    if not parts or ''.join(n + u for n, u in parts) != text:
+        return None
+    return sum(int(n) * duration_units[u] for n, u in parts)
+
+def percentile(sorted_samples, p):
+    """Returns the p-th percentile (nearest rank) of a non-empty
+    sorted list."""
+    rank = max(1, -(-p * len(sorted_samples) // 100))
+    return sorted_samples[rank - 1]
+
+class LatencyStats(object):
+    """Remembers the most recent script execution times per key in
+    ring buffers.  Only the most recently used keys are kept."""
+    max_samples = 256
+    max_keys = 64
+
+    def __init__(self):
+        # Maps keys to [samples, index of the next sample to replace].
+        self.buffers = collections.OrderedDict()
+
+    def record(self, key, seconds):
+        entry = self.buffers.pop(key, None)
+        if entry is None:
+            entry = [[], 0]
+        samples, pos = entry
+        if len(samples) < self.max_samples:
+            samples.append(seconds)
+        else:
+            samples[pos] = seconds
+        entry[1] = (pos + 1) % self.max_samples
+        self.buffers[key] = entry
+        while len(self.buffers) > self.max_keys:
+            self.buffers.popitem(last=False)
+
+    def summary(self, key):
+        """Returns (number of samples, p50, p95, max)."""
+        samples = sorted(self.buffers[key][0])
+        return (len(samples), percentile(samples, 50),
+                percentile(samples, 95), samples[-1])
+
+    def keys(self):
+        return list(self.buffers.keys())
+
+class NickServIdentifier(object):
+    """Decides when to identify with NickServ again.  Retries are
+    spaced out exponentially and given up after a few attempts."""
+    max_attempts = 5
+    initial_delay = 30
+
+    def __init__(self, clock):
+        self.clock = clock
+        self.reset()
+
+    def reset(self):
+        """Called after identifying on connect.  NickServ usually asks
+        us to identify right after connecting, so wait a bit before
+        taking that seriously."""
+        self.pending = False
+        self.attempts = 0
+        self.next_attempt = self.clock() + self.initial_delay
+
+    def handle_notice(self, text):
+        if any(p in text for p in nickserv_success_patterns):
+            self.identified()
+        elif any(p in text for p in nickserv_identify_patterns):
+            self.pending = True
+
+    def identification_lost(self):
+        self.pending = True
+
+    def identified(self):
+        self.pending = False
+        self.attempts = 0
+        self.next_attempt = self.clock()
+
+    def poll(self):
+        """Returns True if we should send IDENTIFY now."""
+        now = self.clock()
+        if (not self.pending or self.attempts >= self.max_attempts
+            or now < self.next_attempt):
+            return False
+        self.next_attempt = now + self.initial_delay * 2 ** self.attempts
+        self.attempts += 1
+        return True
+
+class TrafficRecorder(object):
+    """Remembers the most recent raw IRC lines in both directions."""
+    max_lines = 500
+
+    def __init__(self, clock, secrets):
+        self.clock = clock
+        self.secrets = secrets
+        self.lines = collections.deque(maxlen=self.max_lines)
+
+    def record(self, direction, line):
+        self.lines.append((self.clock(), direction,
+                           redact_line(line, self.secrets())))
+
+    def format(self, n):
+        entries = list(self.lines)[-n:] if n > 0 else []
+        return ''.join('%s %s %s\n' % (time.strftime('%Y-%m-%d %H:%M:%S',
+                                                      time.localtime(t)),
+                                       direction, line)
+                       for t, direction, line in entries)
+
 class SimpleBot(SingleServerIRCBot):
-    def __init__(self, channels, nickname, nickpass, server, port=6667):
-        SingleServerIRCBot.__init__(self, [(server, port)], nickname, nickname)
+    def __init__(self, channels, nickname, nickpass, server, port=6667,
+                 channel_keys={}, topic_channels=None, audit_target=None,
+                 tls=False, tls_verify=True):
+        connect_params = {}
+        if tls:
+            context = ssl.create_default_context()
+            if not tls_verify:
+                context.check_hostname = False
+                context.verify_mode = ssl.CERT_NONE
+            connect_params['connect_factory'] = Factory(
+                wrapper=functools.partial(context.wrap_socket,
+                                          server_hostname=server))
+        # The irc library reconnects by itself.  Our state lives in
+        # this object and survives, on_welcome identifies and rejoins.
+        SingleServerIRCBot.__init__(
+            self, [(server, port)], nickname, nickname,
+            recon=ExponentialBackoff(min_interval=reconnect_min_interval,
+                                     max_interval=reconnect_max_interval),
+            **connect_params)
+        # Sending blocks when we are too fast.  The connection object
+        # survives reconnects, so this is done only once.
+        self.connection.set_rate_limit(send_rate_limit)
         self.initial_channels = channels
+        self.channel_keys = channel_keys
+        # Channels with a word of the day in the topic.
+        if topic_channels is None:
+            topic_channels = channels[:1]
+        self.topic_channels = topic_channels
+        # Admin commands are reported to this nick or channel.
+        self.audit_target = audit_target
         self.nickpass = nickpass
         # magic_key is used for admin commands. E.g., "magic_key say
         # test" in a query with the bot triggers the admin command
         # "say".
         self.magic_key = ''.join([random.choice(string.ascii_letters) for x in range(8)]) + ' '
-        self.print_magic_key()
-        self.current_topic = ''
+        # All code depending on the current time asks this function,
+        # so it can be replaced by a fake clock.
+        self.clock = time.time
+        self.logger = Logger(clock=lambda: self.clock())
+        self.nickserv = NickServIdentifier(lambda: self.clock())
+        self.traffic = TrafficRecorder(
+            lambda: self.clock(),
+            lambda: [self.nickpass, self.magic_key.strip()])
+        # Record everything we send.
+        send_raw = self.connection.send_raw
+        def recording_send_raw(line):
+            self.traffic.record('>', line)
+            send_raw(line)
+        self.connection.send_raw = recording_send_raw
+        self.publish_magic_key()
+        # Maps lowercase topic channel names to their topics.
+        self.current_topics = {}
+        # Maps lowercase channels to (marker, new word, old word) while
+        # we wait for the server to confirm a new topic.
+        self.pending_topic_words = {}
         self._timers = []
+        self.rng = random.Random()
+        self.start_time = self.clock()
+        # Maps script paths to the number of consecutive failures.
+        self.script_failures = {}
+        # Maps script paths to the time they were disabled.
+        self.disabled_scripts = {}
+        # Set while we are disconnected, for offline_notice.
+        self.offline_since = None
+        self.offline_duration = None
+        self.offline_notice_channels = set()
+        self.last_state_save = None
+        try:
+            self.state = state_from_json(load_json(bot_state_file, {}))
+        except ValueError as e:
+            print(_('Warning: Ignoring broken %s: %s') % (bot_state_file, e))
+            self.state = {}
+        # Timers from before a restart.  They move to _timers once we
+        # are back in their channel, overdue ones run right away.
+        # Timers for channels we don't join are dropped.
+        def joinable(target):
+            return not is_channel(target) or irc_lower(target) in [
+                irc_lower(c) for c in channels]
+        self.restored_timers = [
+            t for t in timers_from_json(self.state.get('timers', []))
+            if joinable(t[4])]
+        # Scripts with timers before a restart, as [path, target].
+        # notify_restart() tells them before their timers run.
+        self.restart_notifications = [
+            [p, t] for p, t
+            in timer_targets_from_json(self.state.pop('timer_targets', []))
+            if joinable(t)]
+        if offline_notice:
+            # running is still set if we did not exit cleanly.
+            if self.state.get('running') and 'last_seen' in self.state:
+                self.offline_since = self.state['last_seen']
+            self.state['running'] = True
+            self.save_state()
+        # Scripts that cannot be run, e.g. missing the executable bit.
+        self.broken_scripts = set()
+        for s in scripts:
+            self.check_script(s[1])
+        # Execution times by (script path, channel, timer or not).
+        self.latency = LatencyStats()
+        # For the watchdog.
+        self.last_progress = self.clock()
+        self.last_sd_notify = None
+        self.last_message_time = None
+        # Scripts running in their own threads.  The threads pass the
+        # results to the main loop through script_results.
+        self.running_scripts = []
+        self.script_results = queue.Queue()
+        # Runs waiting for their turn, oldest first.
+        self.queued_scripts = []
+        # Lowercase nicks with user mode +B according to WHO replies.
+        self.detected_bots = set()
+        # Maps lowercase channels with new users to (channel, time of
+        # the WHO).  See who_delay.
+        self.pending_whos = {}
+        # Lowercase nicks that are never considered to be bots.
+        self.trusted_nicks = set()
+        # Lowercase nicks we don't answer.  Nicks added at runtime are
+        # kept in the state.
+        self.ignored_nicks = set(irc_lower(n) for n in ignored_nicks)
+        self.ignored_nicks.update(self.state.get('ignored_nicks', []))
+        # User modes to restore after a reconnect.
+        self.user_modes = set(bot_user_modes.lstrip('+'))
+        # Lowercase channels where we have operator status.
+        self.op_channels = set()
+        # "!user@host" as others see it, known after our first JOIN.
+        self.own_userhost = None
+        # Maps links to the time we last fetched their title.
+        self.url_titles_seen = {}
+        # (channel, message) from the threads fetching titles.
+        self.url_titles = queue.Queue()
+        self.url_title_fetches = threading.BoundedSemaphore(
+            url_title_max_fetches)
+        # Run scripts even in moderated channels where we cannot speak.
+        self.ignore_moderation = False
+        # Set when shutting down, see shutdown_output_timeout.
+        self.shutdown_deadline = None
         self._connect()
 
-    def print_magic_key(self):
-        print(_('Today\'s magic key for admin commands: %s') % self.magic_key, end=' ')
-        sys.stdout.flush()
+    def publish_magic_key(self):
+        """Shows the magic key once on stderr and stores it in a file
+        only we can read.  It must not end up in regular logs."""
+        sys.stderr.write(_('Today\'s magic key for admin commands: %s')
+                         % self.magic_key + '\n')
+        sys.stderr.flush()
+        fd = os.open(magic_key_file, os.O_WRONLY | os.O_CREAT | os.O_TRUNC,
+                     0o600)
+        # The file may have existed with other permissions.
+        os.fchmod(fd, 0o600)
+        with os.fdopen(fd, 'w') as f:
+            f.write(self.magic_key.strip() + '\n')
 
     def debug_out(self, line):
-        # Overwrite magic key.
-        print('\r' + (60 * ' ') + '\r' + line)
-        # Print magic key again.
-        self.print_magic_key()
+        self.logger.log(line)
 
-    def say(self, lines, to=None):
+    def say(self, lines, to=None, max_lines=max_channel_lines):
         if to is None:
             to = self.say_target
-        # Limit maximum number of lines and line length.
-        for line in lines.splitlines()[:4]:
-            self.connection.privmsg(to, limit_length(line, 410))
+        for line in split_message(lines, self.message_budget(to), max_lines):
+            if (self.shutdown_deadline is not None
+                    and self.clock() > self.shutdown_deadline):
+                self.debug_out(_('Shutting down, dropped output to %s.')
+                               % to)
+                return
+            self.connection.privmsg(to, line)
+
+    def message_budget(self, target, command='PRIVMSG'):
+        """Returns how many bytes of text fit into one message to
+        target."""
+        # The irc library keeps the ISUPPORT tokens in features.
+        line_length = getattr(self.connection.features, 'linelen', None)
+        try:
+            line_length = int(line_length)
+        except (TypeError, ValueError):
+            line_length = default_line_length
+        userhost = self.own_userhost
+        if userhost is None:
+            # Assume the longest user and host names servers allow.
+            userhost = '!' + 'u' * 10 + '@' + 'h' * 63
+        prefix_length = len((self.connection.get_nickname()
+                             + userhost).encode('utf-8'))
+        return message_budget(line_length, command, target, prefix_length)
 
     def on_nicknameinuse(self, c, e):
         c.nick(c.get_nickname() + '_')
@@ -110,33 +1178,319 @@ class SimpleBot(SingleServerIRCBot):
     def on_welcome(self, c, e):
         if self.nickpass is not None:
             c.privmsg('NickServ', 'identify ' + self.nickpass)
-        # Set bot mode.
-        c.mode(c.get_nickname(), '+B')
+            self.nickserv.reset()
+        # Restore our user modes.
+        c.mode(c.get_nickname(), '+' + ''.join(sorted(self.user_modes)))
+        if self.offline_since is not None:
+            self.offline_duration = self.clock() - self.offline_since
+            self.offline_notice_channels = set(
+                irc_lower(channel) for channel in self.initial_channels)
+            self.offline_since = None
         for channel in self.initial_channels:
-            c.join(channel)
+            c.join(channel, self.channel_keys.get(channel, ''))
+        for target in (set(t[4] for t in self.restored_timers)
+                       | set(t for p, t in self.restart_notifications)):
+            if not is_channel(target):
+                self.restore_timers(target)
+
+    def on_join(self, c, e):
+        # The WHO replies tell us who has user mode +B.
+        if e.source.nick == c.get_nickname():
+            self.own_userhost = e.source[len(e.source.nick):]
+            c.who(e.target)
+            # Ask for the channel modes, we need to know about +m.
+            c.mode(e.target, '')
+            if chanserv is not None and irc_lower(e.target) in self.op_channels:
+                c.privmsg(chanserv, 'OP ' + e.target)
+            if irc_lower(e.target) in self.offline_notice_channels:
+                self.offline_notice_channels.remove(irc_lower(e.target))
+                self.post_offline_notice(e.target)
+            self.restore_timers(e.target)
+        elif irc_lower(e.target) not in self.pending_whos:
+            self.pending_whos[irc_lower(e.target)] = (
+                e.target, self.clock() + who_delay)
+
+    def send_pending_whos(self):
+        now = self.clock()
+        for key, (channel, due) in list(self.pending_whos.items()):
+            if due <= now:
+                del self.pending_whos[key]
+                self.connection.who(channel)
+
+    def on_disconnect(self, c, e):
+        if offline_notice and self.offline_since is None:
+            self.offline_since = self.clock()
+
+    def post_offline_notice(self, channel):
+        """Tells channel that we were offline, at most once per
+        offline_notice_interval."""
+        now = self.clock()
+        notices = self.state.setdefault('offline_notices', {})
+        last = notices.get(irc_lower(channel))
+        if last is not None and now - last < offline_notice_interval:
+            return
+        notices[irc_lower(channel)] = now
+        self.save_state()
+        with use_language(channel_language(channel)):
+            self.say(_('I was briefly offline (%ds); please resend any '
+                       'commands.') % self.offline_duration, to=channel)
+
+    def restore_timers(self, target):
+        """Activates the timers for target from before a restart after
+        telling their scripts about the restart.  Runs for the same
+        target are queued in order, so the scripts see --bot-restarted
+        first."""
+        self.notify_restart(target)
+        for timer in list(self.restored_timers):
+            if irc_lower(timer[4]) == irc_lower(target):
+                self.restored_timers.remove(timer)
+                self._timers.append(timer)
+
+    def notify_restart(self, target):
+        """Runs scripts that had timers for target before a restart
+        with the argument --bot-restarted, so they can end or
+        re-announce their round."""
+        paths = [p for p, t in self.restart_notifications
+                 if irc_lower(t) == irc_lower(target)]
+        self.restart_notifications = [
+            [p, t] for p, t in self.restart_notifications
+            if irc_lower(t) != irc_lower(target)]
+        source_target = (self.connection.get_nickname(), target)
+        for path in paths:
+            self.say_target = target
+            try:
+                with use_language(channel_language(target)):
+                    self.start_script(path, '--bot-restarted',
+                                      source_target, from_timer=True)
+            except Exception as e:
+                self.debug_out(_('Restart notification for %s failed: %s')
+                               % (path, e))
+
+    def save_state(self):
+        self.last_state_save = self.clock()
+        try:
+            save_json(bot_state_file, self.state)
+        except OSError as e:
+            self.debug_out(_('Could not save the state: %s') % e)
+
+    def on_umode(self, c, e):
+        for sign, mode, argument in parse_user_modes(' '.join(e.arguments)):
+            # +r means we are identified with NickServ.
+            if mode == 'r':
+                if sign == '+':
+                    self.nickserv.identified()
+                else:
+                    self.nickserv.identification_lost()
+            if mode in server_user_modes:
+                continue
+            if sign == '+':
+                self.user_modes.add(mode)
+            else:
+                self.user_modes.discard(mode)
+
+    def on_all_raw_messages(self, c, e):
+        self.traffic.record('<', e.arguments[0])
+
+    def on_privnotice(self, c, e):
+        if irc_lower(e.source.nick) == 'nickserv':
+            self.nickserv.handle_notice(e.arguments[0])
+
+    def on_mode(self, c, e):
+        nickname = irc_lower(c.get_nickname())
+        for sign, mode, argument in parse_channel_modes(' '.join(e.arguments)):
+            if (mode != 'o' or argument is None
+                    or irc_lower(argument) != nickname):
+                continue
+            if sign == '+':
+                self.op_channels.add(irc_lower(e.target))
+            else:
+                self.op_channels.discard(irc_lower(e.target))
+
+    def on_part(self, c, e):
+        if irc_lower(e.source.nick) == irc_lower(c.get_nickname()):
+            self.left_channel(e.target)
+
+    def on_kick(self, c, e):
+        if irc_lower(e.arguments[0]) == irc_lower(c.get_nickname()):
+            self.left_channel(e.target)
+
+    def left_channel(self, channel):
+        # We won't be back after a reconnect, so don't ask for op.
+        self.op_channels.discard(irc_lower(channel))
+
+    def on_channelmodeis(self, c, e):
+        # Arguments: channel, modes, mode arguments...
+        channel = self.channels.get(e.arguments[0])
+        if channel is None:
+            return
+        for sign, mode, argument in parse_channel_modes(
+                ' '.join(e.arguments[1:])):
+            if sign == '+':
+                channel.set_mode(mode, argument)
+            else:
+                channel.clear_mode(mode, argument)
+
+    def can_speak(self, target):
+        """Returns False if target is a moderated channel where we have
+        neither voice nor operator status."""
+        channel = self.channels.get(target)
+        if channel is None or not channel.is_moderated():
+            return True
+        nickname = self.connection.get_nickname()
+        return channel.is_voiced(nickname) or channel.is_oper(nickname)
+
+    def on_whoreply(self, c, e):
+        # Arguments: channel, user, host, server, nick, flags, ...
+        nick, flags = e.arguments[4], e.arguments[5]
+        if 'B' in flags:
+            self.detected_bots.add(irc_lower(nick))
+        else:
+            self.detected_bots.discard(irc_lower(nick))
+
+    def on_nick(self, c, e):
+        old_nick = irc_lower(e.source.nick)
+        if old_nick in self.detected_bots:
+            self.detected_bots.remove(old_nick)
+            self.detected_bots.add(irc_lower(e.target))
+
+    def is_bot(self, nick):
+        nick = irc_lower(nick)
+        if nick in self.trusted_nicks:
+            return False
+        return (nick in [irc_lower(n) for n in known_bots]
+                or nick in self.detected_bots
+                or looks_like_bot(nick))
+
+    def is_ignored(self, nick):
+        return irc_lower(nick) in self.ignored_nicks
+
+    def set_ignored(self, nick, ignored):
+        nick = irc_lower(nick)
+        runtime_nicks = set(self.state.get('ignored_nicks', []))
+        if ignored:
+            self.ignored_nicks.add(nick)
+            runtime_nicks.add(nick)
+        else:
+            self.ignored_nicks.discard(nick)
+            runtime_nicks.discard(nick)
+        self.state['ignored_nicks'] = sorted(runtime_nicks)
+        self.save_state()
+
+    def is_admin_command(self, line):
+        if line[:1] == '!':
+            line = line[1:]
+        return line.startswith(self.magic_key)
+
+    def resolve_target(self, e):
+        """Messages can be sent to several targets at once, e.g.
+        "nick,#channel".  Replaces such a target by a single one,
+        preferring channels we are in.  Returns False if no target is
+        ours."""
+        if ',' not in e.target:
+            return True
+        targets = [t for t in e.target.split(',') if t]
+        for target in targets:
+            if target in self.channels:
+                e.target = target
+                return True
+        nickname = self.connection.get_nickname()
+        if irc_lower(nickname) in [irc_lower(t) for t in targets]:
+            e.target = nickname
+            return True
+        return False
+
+    def get_version(self):
+        """The irc library answers CTCP VERSION and PING with NOTICEs
+        and asks us for the version."""
+        return ctcp_version
+
+    def on_action(self, c, e):
+        # "/me help" in a query works like "help".  Actions in
+        # channels are just chatter.
+        if not is_channel(e.target):
+            self.handle_message(c, e)
 
     def on_privmsg(self, c, e):
+        self.handle_message(c, e)
+
+    def on_pubmsg(self, c, e):
+        self.handle_message(c, e)
+
+    def handle_message(self, c, e):
+        self.last_message_time = self.clock()
+        if not self.resolve_target(e):
+            return
+        # The admin may have a nick like a bot, e.g. to run trustbot.
+        if (self.is_bot(e.source.nick)
+                and not self.is_admin_command(e.arguments[0])):
+            return
+        if e.target == c.get_nickname():
+            self.handle_private_message(e)
+        else:
+            self.handle_channel_message(e)
+
+    def handle_private_message(self, e):
         self.current_event = e
         self.say_target = e.source.nick
         line = e.arguments[0]
         if len(line) > 0 and line[0] == '!':
             line = line[1:]
         self.do_command(line)
-        self.debug_out('<%s> %s' % (e.source, line))
+        self.debug_out('<%s> %s' % (e.source,
+                                    redact_line(line, [self.magic_key.strip()])))
 
-    def on_pubmsg(self, c, e):
+    def handle_channel_message(self, e):
         self.current_event = e
         a = e.arguments[0]
         if len(a) > 0 and a[0] == '!':
             self.say_target = e.target
             self.do_command(a[1:])
+        elif (irc_lower(e.target) in [irc_lower(c) for c in url_title_channels]
+              and not self.is_ignored(e.source.nick)):
+            self.fetch_url_titles(e.target, a)
         return
 
+    def fetch_url_titles(self, channel, text):
+        """Fetches titles in the background, post_url_titles() sends
+        them."""
+        now = self.clock()
+        self.url_titles_seen = {
+            url: t for url, t in self.url_titles_seen.items()
+            if now - t < url_title_repeat }
+        for url in find_urls(text)[:url_title_max_links]:
+            if url in self.url_titles_seen:
+                continue
+            # Skip the link if too many fetches are running.
+            if not self.url_title_fetches.acquire(blocking=False):
+                return
+            self.url_titles_seen[url] = now
+            threading.Thread(target=self.fetch_url_title,
+                             args=(channel, url), daemon=True).start()
+
+    def fetch_url_title(self, channel, url):
+        try:
+            title = fetch_title(url)
+        finally:
+            self.url_title_fetches.release()
+        if title is not None:
+            host = urllib.parse.urlsplit(url).hostname
+            self.url_titles.put((channel, '↪ %s — %s' % (title, host)))
+
+    def post_url_titles(self):
+        while True:
+            try:
+                channel, line = self.url_titles.get_nowait()
+            except queue.Empty:
+                return
+            if channel in self.channels:
+                self.say(line, to=channel)
+
     def do_command(self, cmd):
         """This method will never raise an exception based on the
         Exception base class."""
         try:
-            self.do_command_unsafe(cmd)
+            with use_language(channel_language(self.say_target)):
+                self.do_command_unsafe(cmd)
         except Exception as e:
             output = io.StringIO()
             output.write(_('Caught exception: %s\n') % str(e))
@@ -147,14 +1501,51 @@ class SimpleBot(SingleServerIRCBot):
     def do_command_unsafe(self, cmd):
         """This method could raise an exception."""
         if cmd[0:len(self.magic_key)] == self.magic_key:
+            self.audit(cmd[len(self.magic_key):])
             self.do_special_command(cmd[len(self.magic_key):])
         else:
+            if self.is_ignored(self.current_event.source.nick):
+                return
+            words = cmd.split(' ', 2)
+            # Looks like somebody guessing the magic key.
+            if (len(words) >= 2
+                    and len(words[0]) == len(self.magic_key.strip())
+                    and re.fullmatch('[A-Za-z]+', words[0])
+                    and words[1] in admin_commands
+                    and find_script(words[0]) is None):
+                self.audit(' '.join(words[1:]), rejected=True)
             self.do_user_command(cmd)
 
+    def audit(self, cmd, rejected=False):
+        """Reports an admin command to the audit target."""
+        if self.audit_target is None:
+            return
+        nick = self.current_event.source.nick
+        line = '%s %s: %s' % (
+            time.strftime('%Y-%m-%d %H:%M:%S', time.localtime(self.clock())),
+            nick, cmd)
+        if rejected:
+            line = _('%s (wrong key)') % line
+        try:
+            self.connection.notice(self.audit_target, limit_length(
+                line, self.message_budget(self.audit_target, 'NOTICE')))
+        except Exception as e:
+            self.debug_out(_('Could not send audit line: %s') % e)
+
     def do_special_command(self, cmd):
         """Commands only the admin may use."""
         cmd = cmd.split(' ', 1)
         if cmd[0] == 'die':
+            # Let scripts finish so they can save their state, then
+            # send their output.
+            self.queued_scripts = []
+            self.wait_for_scripts(script_shutdown_timeout)
+            self.kill_scripts()
+            self.shutdown_deadline = self.clock() + shutdown_output_timeout
+            self.finish_scripts()
+            if offline_notice:
+                self.state['running'] = False
+                self.save_state()
             if len(cmd) == 1:
                 self.die('さようなら')
             else:
@@ -168,6 +1559,32 @@ class SimpleBot(SingleServerIRCBot):
         elif cmd[0] == 'privmsg':
             cmd = cmd[1].split(' ', 1)
             self.connection.privmsg(cmd[0], cmd[1])
+        elif cmd[0] == 'trustbot':
+            self.trusted_nicks.add(irc_lower(cmd[1]))
+            self.say(_('%s is no longer treated as a bot.') % cmd[1])
+        elif cmd[0] == 'latency':
+            self.show_latency(cmd[1] if len(cmd) == 2 else None)
+        elif cmd[0] == 'wotd':
+            self.do_word_of_the_day_command(cmd[1] if len(cmd) == 2 else '')
+        elif cmd[0] == 'dump-traffic':
+            self.dump_traffic(int(cmd[1]) if len(cmd) == 2
+                              else TrafficRecorder.max_lines)
+        elif cmd[0] == 'ignore-moderation':
+            self.ignore_moderation = len(cmd) == 1 or cmd[1] != 'off'
+            self.say(_('Ignoring +m: %s') % self.ignore_moderation)
+        elif cmd[0] == 'ignore':
+            self.set_ignored(cmd[1], True)
+            self.say(_('Ignoring %s.') % cmd[1])
+        elif cmd[0] == 'unignore':
+            self.set_ignored(cmd[1], False)
+            self.say(_('No longer ignoring %s.') % cmd[1])
+        elif cmd[0] == 'enable-script':
+            path = find_script(cmd[1])
+            if path is None:
+                self.say(_('Unknown command.'))
+            else:
+                self.enable_script(path)
+                self.say(_('Enabled %s.') % path)
         else:
             self.say(_('Unknown command.'))
 
@@ -175,17 +1592,35 @@ class SimpleBot(SingleServerIRCBot):
         e = self.current_event
         source = e.source.nick
         target = e.target
+        # Some clients send decomposed characters.  Normalize the nicks
+        # so scripts see the same nick either way.
+        normalized_source = unicodedata.normalize('NFC', source)
         if target == self.connection.get_nickname():
-            return (source, source)
+            return (normalized_source, normalized_source)
         else:
-            return (source, target)
+            return (normalized_source, target)
 
     def do_user_command(self, cmd):
         """Commands normal users may use."""
+        name = re.split('[ 　]', unicodedata.normalize('NFC', cmd), 1)[0]
+        if command_disabled(name, self.say_target):
+            return
         if cmd == 'version':
             return self.say(_('A very simple bot with 日本語 support.'))
         elif cmd == 'help':
             return self.show_help()
+        elif cmd == 'uptime':
+            return self.say(_('Up for %s.') % format_duration(
+                self.clock() - self.start_time))
+        elif cmd == 'channels':
+            # Secret (+s) and private (+p) channels stay hidden.
+            channels = sorted(c for c, channel in self.channels.items()
+                              if not channel.has_mode('s')
+                              and not channel.has_mode('p'))
+            if not channels:
+                return self.say(_('Not in any channel.'))
+            return self.say(format_list(channels,
+                                        self.message_budget(self.say_target)))
         split_pos = cmd.find(' ')
         split_pos2 = cmd.find('　')
         split_pos_len = len(' ')
@@ -196,119 +1631,604 @@ class SimpleBot(SingleServerIRCBot):
             cmd = [cmd, '']
         else:
             cmd = [cmd[:split_pos], cmd[split_pos + split_pos_len:]]
-        e = self.current_event
-        for s in scripts:
-            name = s[0]
-            if type(name) is not list:
-                name = [name]
-            if cmd[0] in name:
-                output = run_script(s[1], cmd[1], self.get_source_target())
-                self.handle_script_output(output, s[1])
-
-    def handle_script_output(self, output, script):
+        if cmd[0] in ['remind', 'remindme']:
+            return self.do_remind_command(cmd[1])
+        path = find_script(unicodedata.normalize('NFC', cmd[0]))
+        if path is not None:
+            if (not self.ignore_moderation
+                    and not self.can_speak(self.say_target)):
+                # Don't waste a script run on output nobody would see.
+                self.connection.notice(
+                    self.current_event.source.nick,
+                    _('I can\'t speak in %s right now.') % self.say_target)
+                return
+            self.start_script(path, cmd[1], self.get_source_target(),
+                              sender=self.current_event.source.nick)
+
+    def start_script(self, path, argument, irc_source_target, sender=None,
+                     from_timer=False, extra_env={}):
+        """Starts the script in its own thread unless it has been
+        disabled because of too many failures.  If the script is
+        running too often already, the run waits in queued_scripts.
+        finish_scripts() sends the output to say_target."""
+        # Check again so that a fixed script works without a restart.
+        if path in self.broken_scripts and not self.check_script(path):
+            return self.say(_('This command is temporarily unavailable.'))
+        disabled_time = self.disabled_scripts.get(path)
+        if disabled_time is not None:
+            if self.clock() - disabled_time < disabled_script_cooldown:
+                return self.say(_('This command is temporarily disabled.'))
+            self.enable_script(path)
+        if (len([j for j in self.queued_scripts if j.path == path])
+                >= max_queued_runs):
+            if from_timer:
+                # Nobody asked, so don't tell the channel.
+                return self.debug_out(_('Dropped timer for %s, too many '
+                                        'queued runs.') % path)
+            return self.say(_('This command is busy, please try again later.'))
+        self.queued_scripts.append(ScriptJob(
+            path, argument, irc_source_target, self.say_target, sender,
+            from_timer, extra_env, current_language(),
+            self.connection.get_nickname(), None))
+        self.run_queued_scripts()
+
+    def run_queued_scripts(self):
+        """Starts the queued runs whose turn it is.  A run waits while
+        the script runs for the same target, e.g. a quiz in the same
+        channel, or max_concurrent times in total."""
+        waiting = []
+        for job in self.queued_scripts:
+            limit = script_options(job.path).get('max_concurrent',
+                                                 max_concurrent_runs)
+            busy = self.running_scripts + waiting
+            if (any(j.path == job.path
+                    and irc_lower(j.target) == irc_lower(job.target)
+                    for j in busy)
+                    or len([j for j in self.running_scripts
+                            if j.path == job.path]) >= limit):
+                # Later runs for the same target must not overtake it.
+                waiting.append(job)
+                continue
+            job = job._replace(start_time=self.clock())
+            self.running_scripts.append(job)
+            threading.Thread(target=self.script_thread, args=(job,),
+                             daemon=True).start()
+        self.queued_scripts = waiting
+
+    def script_thread(self, job):
+        with use_language(job.language):
+            result = call_script(job.path, job.argument, job.source_target,
+                                 job.bot_nick, job.extra_env,
+                                 timeout=script_timeout)
+        self.script_results.put((job, result))
+
+    def finish_scripts(self):
+        """Handles the results of finished scripts."""
+        while True:
+            try:
+                job, (output, errors, success) = \
+                    self.script_results.get_nowait()
+            except queue.Empty:
+                return
+            self.running_scripts.remove(job)
+            self.run_queued_scripts()
+            self.say_target = job.target
+            try:
+                with use_language(job.language):
+                    output = self.script_finished(job, output, errors,
+                                                  success)
+                    self.handle_script_output(output, job.path, job.sender,
+                                              job.source_target)
+            except Exception:
+                self.debug_out(traceback.format_exc())
+
+    def wait_for_scripts(self, timeout):
+        """Gives running scripts some time to finish, e.g. before
+        shutting down.  finish_scripts() sends their output."""
+        end = self.clock() + timeout
+        while (self.script_results.qsize() < len(self.running_scripts)
+               and self.clock() < end):
+            time.sleep(0.1)
+
+    def kill_scripts(self):
+        """Kills the scripts that are still running.  They run in
+        their own process groups and would survive the bot."""
+        for process in list(running_processes):
+            kill_process_group(process)
+            self.debug_out(_('Killed %s.') % process.args[0])
+
+    def script_finished(self, job, output, errors, success):
+        """Logs stderr and keeps track of failures.  Returns the output
+        to send."""
+        path = job.path
+        options = script_options(path)
+        if errors.strip():
+            if options.get('stderr_to_channel', False):
+                output = output + errors
+            else:
+                self.debug_out(_('stderr of %s: %s') % (
+                    path, limit_length(errors.strip(), max_logged_stderr)))
+        self.latency.record((path, job.source_target[1], job.from_timer),
+                            self.clock() - job.start_time)
+        if success:
+            self.script_failures.pop(path, None)
+            return output
+        if not self.check_script(path):
+            return _('This command is temporarily unavailable.')
+        failures = self.script_failures.get(path, 0) + 1
+        self.script_failures[path] = failures
+        # Runs that were already going may fail after we disabled the
+        # script, report it only once.
+        if (failures >= max_consecutive_failures
+                and path not in self.disabled_scripts):
+            self.disabled_scripts[path] = self.clock()
+            message = (_('Disabled %s after %d consecutive failures.')
+                       % (path, failures))
+            self.debug_out(message)
+            if self.audit_target is not None:
+                try:
+                    self.connection.notice(self.audit_target, message)
+                except Exception as e:
+                    self.debug_out(_('Could not send audit line: %s') % e)
+        return output
+
+    def pending_reminders(self, nick):
+        """Returns the reminders of nick, the earliest first."""
+        nick = irc_lower(nick)
+        return sorted(t for t in self._timers
+                      if t[1] is None and irc_lower(t[3][0]) == nick)
+
+    def do_remind_command(self, args):
+        nick = self.get_source_target()[0]
+        args = args.strip()
+        if args == 'list':
+            reminders = self.pending_reminders(nick)
+            if not reminders:
+                return self.say(_('No pending reminders.'))
+            self.say('\n'.join(
+                '%d. %s %s' % (i + 1, time.strftime(
+                    '%Y-%m-%d %H:%M', time.localtime(t[0])), t[2])
+                for i, t in enumerate(reminders)))
+            return
+        split = args.split(None, 1)
+        if len(split) == 2 and split[0] == 'cancel':
+            reminders = self.pending_reminders(nick)
+            n = int(split[1]) if split[1].isdigit() else 0
+            if not 1 <= n <= len(reminders):
+                return self.say(_('No such reminder.'))
+            timer = reminders[n - 1]
+            self._timers.remove(timer)
+            self.save_timers()
+            return self.say(_('Canceled reminder: %s') % timer[2])
+        delay = parse_duration(split[0]) if split else None
+        if len(split) != 2 or delay is None:
+            return self.say(_('Usage: remind <duration> <message> | '
+                              'remind list | remind cancel <n>'))
+        if delay > max_reminder_delay:
+            return self.say(_('Reminders can be at most %s away.')
+                            % format_duration(max_reminder_delay))
+        if len(self.pending_reminders(nick)) >= max_reminders_per_user:
+            return self.say(_('You already have %d pending reminders.')
+                            % max_reminders_per_user)
+        # The script None marks the timer as a reminder.
+        self.add_timer(delay, None, split[1])
+        self.say(_('I will remind you at %s.') % time.strftime(
+            '%Y-%m-%d %H:%M', time.localtime(self.clock() + delay)))
+
+    def do_word_of_the_day_command(self, args):
+        args = args.split()
+        if len(args) == 3 and args[0] == 'add' and is_word(args[2]):
+            pool = self.load_word_pool()
+            pool.append({ 'word': args[2], 'category': args[1],
+                          'last_used': None })
+            save_word_pool(word_of_the_day_pool, pool)
+            self.say(_('Added %s to %s.') % (args[2], args[1]))
+        elif args == ['skip']:
+            for channel in self.topic_channels:
+                self.rotate_word_of_the_day(channel)
+        elif args == ['stats']:
+            latest = (self.today() - datetime.timedelta(
+                days=word_of_the_day_min_days)).isoformat()
+            total = collections.Counter()
+            available = collections.Counter()
+            for entry in self.load_word_pool():
+                category = entry.get('category', 'default')
+                total[category] += 1
+                last_used = entry.get('last_used')
+                if last_used is None or last_used <= latest:
+                    available[category] += 1
+            if not total:
+                return self.say(_('No data.'))
+            self.say(', '.join('%s: %d/%d' % (c, available[c], total[c])
+                               for c in sorted(total)))
+        else:
+            self.say(_('Usage: wotd add <category> <word> | wotd skip | wotd stats'))
+
+    def dump_traffic(self, n):
+        """Writes raw IRC traffic to a file.  Never send it to IRC, it
+        may contain private messages."""
+        path = os.path.abspath(time.strftime(
+            'traffic-%Y%m%d-%H%M%S.txt', time.localtime(self.clock())))
+        with open(path, 'w') as f:
+            f.write(self.traffic.format(n))
+        self.say(_('Wrote traffic to %s.') % path)
+
+    def show_latency(self, command):
+        path = None
+        if command is not None:
+            path = find_script(command)
+            if path is None:
+                return self.say(_('Unknown command.'))
+        lines = []
+        for key in sorted(self.latency.keys()):
+            if path is not None and key[0] != path:
+                continue
+            n, p50, p95, maximum = self.latency.summary(key)
+            origin = ' (timer)' if key[2] else ''
+            lines.append('%s %s%s: n=%d p50=%.2fs p95=%.2fs max=%.2fs'
+                         % (key[0], key[1], origin, n, p50, p95, maximum))
+        if not lines:
+            return self.say(_('No data.'))
+        self.say('\n'.join(lines))
+
+    def today(self):
+        return datetime.date.fromtimestamp(self.clock())
+
+    def check_script(self, path):
+        """Marks path as broken or working.  Returns True if it looks
+        runnable."""
+        problem = script_problem(path)
+        if problem is None:
+            self.broken_scripts.discard(path)
+            return True
+        if path not in self.broken_scripts:
+            self.debug_out(_('Warning: %s %s.') % (path, problem))
+            self.broken_scripts.add(path)
+        return False
+
+    def enable_script(self, path):
+        self.disabled_scripts.pop(path, None)
+        self.script_failures.pop(path, None)
+
+    def handle_script_output(self, output, script, sender=None,
+                             source_target=None):
+        """Sends the output.  Lines that don't fit go to sender if the
+        script asks for it.  Timers have no sender."""
         result = []
+        invalid_timers = 0
         for line in output.split('\n'):
             if not line.startswith('/timer '):
                 result.append(line)
-            else:
-                args = line.split(' ')
-                self.add_timer(int(args[1]), script, args[2])
+                continue
+            try:
+                delay, argument = parse_timer_directive(line)
+            except ValueError as e:
+                self.debug_out(_('Invalid timer from %s: "%s": %s')
+                               % (script, line, e))
+                invalid_timers += 1
+                continue
+            self.add_timer(delay, script, argument, source_target)
+        # Tell the script author, but not on every timer round.
+        if invalid_timers and sender is not None:
+            result.append(ngettext(
+                'Script emitted %d invalid timer directive (see logs).',
+                'Script emitted %d invalid timer directives (see logs).',
+                invalid_timers) % invalid_timers)
+        lines = split_message('\n'.join(result),
+                              self.message_budget(self.say_target))
+        if (sender is not None and is_channel(self.say_target)
+                and len(lines) > max_channel_lines
+                and script_options(script).get('overflow') == 'pm'):
+            overflow = lines[max_channel_lines:]
+            overflow[0] = _('(continued from %s) %s') % (self.say_target,
+                                                         overflow[0])
+            self.say('\n'.join(lines[:max_channel_lines]))
+            self.say('\n'.join(overflow), to=sender,
+                     max_lines=max_overflow_lines)
+            return
         self.say('\n'.join(result))
 
     def show_help(self):
-        possible_commands = [ '!' + str(s[0]) for s in scripts ] + ['!version']
+        possible_commands = [
+            '!' + str(c) for c in [ s[0] for s in scripts ] +
+            ['channels', 'remind', 'uptime', 'version']
+            if not command_disabled(c, self.say_target) ]
         possible_commands.sort()
         self.say(_('Known commands: ') + ', '.join(possible_commands))
 
-    def add_timer(self, delay_seconds, script, argument):
-        """Adds a new timer."""
-        e = self.current_event
-        timer = (delay_seconds + time.time(), script, argument,
-                 self.get_source_target(), self.say_target)
+    def add_timer(self, delay_seconds, script, argument, source_target=None):
+        """Adds a new timer.  source_target defaults to the current
+        message."""
+        if source_target is None:
+            source_target = self.get_source_target()
+        timer = (delay_seconds + self.clock(), script, argument,
+                 source_target, self.say_target)
         self._timers.append(timer)
+        self.save_timers()
+
+    def save_timers(self):
+        """Saves the timers in the state so that they survive a
+        restart.  The deadlines are wall-clock times.  Also remembers
+        which scripts have timers for which targets, for
+        notify_restart() after a restart."""
+        all_timers = self._timers + self.restored_timers
+        timers = [[t[0], t[1], t[2], list(t[3]), t[4]]
+                  for t in sorted(all_timers, key=lambda t: t[0])]
+        targets = set((t[1], t[4]) for t in all_timers if t[1] is not None)
+        targets.update((p, t) for p, t in self.restart_notifications)
+        targets = [list(t) for t in sorted(targets)]
+        if (self.state.get('timers', []) != timers
+                or self.state.get('timer_targets', []) != targets):
+            self.state['timers'] = timers
+            self.state['timer_targets'] = targets
+            self.save_state()
 
     def run_timed_command(self, timer):
         """Runs the command associated with the timer."""
-        self.say_target = timer[4]
-        self.handle_script_output(run_script(timer[1], timer[2], timer[3]), timer[1])
+        target = timer[4]
+        extra_env = {}
+        if is_channel(target):
+            if target not in self.channels:
+                self.debug_out(_('Skipping timer for %s, not in %s.')
+                               % (timer[1], target))
+                return
+            # Let quiz scripts end their round if fewer than two users
+            # besides us are left.
+            if len(self.channels[target].users()) <= 2:
+                extra_env['DMB_CHANNEL_EMPTY'] = '1'
+        self.say_target = target
+        with use_language(channel_language(target)):
+            if timer[1] is None:
+                return self.say(_('%s: reminder — %s')
+                                % (timer[3][0], timer[2]))
+            self.start_script(timer[1], timer[2], timer[3],
+                              from_timer=True, extra_env=extra_env)
 
     def check_timers(self):
-        current_time = time.time()
+        current_time = self.clock()
         # Check for expired timers.
         [ self.run_timed_command(t) for t in self._timers if t[0] < current_time ]
         # Remove expired timers.
         self._timers = [ t for t in self._timers if t[0] >= current_time ]
+        self.expire_restored_timers()
+        self.save_timers()
+
+    def expire_restored_timers(self):
+        """Drops timers from before a restart whose channel we did not
+        rejoin in time, e.g. because we are banned now."""
+        expired = [t for t in self.restored_timers
+                   if t[0] + restored_timer_expiry < self.clock()]
+        if not expired:
+            return
+        for t in expired:
+            self.debug_out(_('Dropping timer for %s, not back in %s.')
+                           % (t[1] or _('reminder'), t[4]))
+            self.restored_timers.remove(t)
+        targets = set(irc_lower(t[4]) for t in self.restored_timers)
+        self.restart_notifications = [
+            [p, t] for p, t in self.restart_notifications
+            if irc_lower(t) in targets]
+
+    def load_word_pool(self):
+        if not os.path.exists(word_of_the_day_pool):
+            # Take over the words from the old rotation file.
+            return import_word_list('word_of_the_day_next.txt', 'default')
+        return load_word_pool(word_of_the_day_pool)
 
-    def next_word_of_the_day(self, old_word):
-        file_done = 'word_of_the_day_done.txt'
-        file_next = 'word_of_the_day_next.txt'
+    def next_word_of_the_day(self):
+        """Picks the next word.  Returns None if the pool has no word
+        left.  Words waiting for a topic change in another channel are
+        skipped."""
+        pending = [w for m, w, o in self.pending_topic_words.values()]
+        pool = [e for e in self.load_word_pool() if e['word'] not in pending]
+        entry = pick_word_of_the_day(pool, word_of_the_day_weights,
+                                     self.today(), self.rng)
+        if entry is None:
+            return None
+        return entry['word']
+
+    def mark_word_used(self, word):
+        pool = self.load_word_pool()
+        for entry in pool:
+            if entry['word'] == word:
+                entry['last_used'] = self.today().isoformat()
+        save_word_pool(word_of_the_day_pool, pool)
+
+    def announce_word_of_the_day(self, channel, marker, new_word, old_word):
+        """Tells the channel about the new word of the day, but at most
+        once per day."""
+        file_announced = 'word_of_the_day_announced.txt'
+        # The file contains a line "<date> <channel>" for every
+        # announcement today.
+        entry = '%s %s' % (self.today().isoformat(), irc_lower(channel))
         try:
-            f = open(file_next, 'r')
-            f2 = open(file_next + '.tmp', 'w')
-            next_word = f.readline()
-            for line in f:
-                f2.write(line)
-            f.close()
-            f2.close()
-            os.rename(file_next + '.tmp', file_next)
+            with open(file_announced, 'r') as f:
+                announced = [line.strip() for line in f
+                             if line.startswith(entry[:10] + ' ')]
         except IOError:
-            next_word = ''
-        if next_word:
-            f = open(file_done, 'a')
-            f.write(old_word + '\n')
-            f.close()
-        return next_word.strip()
+            announced = []
+        if entry in announced:
+            return
+        with open(file_announced, 'w') as f:
+            f.write(''.join(line + '\n' for line in announced + [entry]))
+        with use_language(channel_language(channel)):
+            self.say('%s%s (%s)' % (marker, new_word,
+                                    _('was: %s') % old_word), to=channel)
+
     def daily_jobs(self):
         """This method will be called once per day a few seconds after
         midnight."""
+        for channel in self.topic_channels:
+            # A broken word pool must not take the bot down.
+            try:
+                self.rotate_word_of_the_day(channel)
+            except Exception as e:
+                self.debug_out(_('Caught exception: %s\n') % str(e)
+                               + traceback.format_exc())
+
+    def rotate_word_of_the_day(self, channel):
         marker = 'Wort des Tages: '
-        if self.current_topic.find(marker) != -1:
-            prefix, old_word = self.current_topic.split(marker, 1)
-            if old_word.find(' ') != -1:
-                print('old_word: ' + old_word)
-                old_word, suffix = old_word.split(' ', 1)
-                suffix = ' ' + suffix
-                print('suffix: ' + suffix)
-            else:
-                suffix = ''
-            new_word = self.next_word_of_the_day(old_word)
-            if new_word:
-                new_topic = '%s%s%s%s' % (prefix, marker, new_word, suffix)
-                print('new topic: [%s]' % new_topic)
-                self.connection.topic(self.initial_channels[0], new_topic)
+        topic = self.current_topics.get(irc_lower(channel), '')
+        if split_topic(topic, marker) is None:
+            return
+        new_word = self.next_word_of_the_day()
+        if new_word:
+            new_topic, old_word = compute_new_topic(topic, marker, new_word)
+            print('new topic: [%s]' % new_topic)
+            # The word is used up once the server confirms the topic,
+            # see confirm_word_of_the_day().
+            self.pending_topic_words[irc_lower(channel)] = (
+                marker, new_word, old_word)
+            self.connection.topic(channel, new_topic)
+
+    def confirm_word_of_the_day(self, channel, topic):
+        """Marks the new word as used and announces it when the server
+        tells us that the topic changed."""
+        pending = self.pending_topic_words.get(irc_lower(channel))
+        if pending is None:
+            return
+        marker, new_word, old_word = pending
+        parts = split_topic(topic, marker)
+        if parts is None or parts[1] != new_word:
+            return
+        del self.pending_topic_words[irc_lower(channel)]
+        try:
+            self.mark_word_used(new_word)
+            if new_word != old_word:
+                self.announce_word_of_the_day(channel, marker, new_word,
+                                              old_word)
+        except Exception as e:
+            self.debug_out(_('Caught exception: %s\n') % str(e)
+                           + traceback.format_exc())
+
+    def on_chanoprivsneeded(self, c, e):
+        # Arguments: channel, message.  We are not allowed to change
+        # the topic, so the word stays unused.
+        if self.pending_topic_words.pop(irc_lower(e.arguments[0]), None):
+            self.debug_out(_('Could not change the topic of %s: %s')
+                           % (e.arguments[0], e.arguments[1]))
 
     def polling_jobs(self):
+        if offline_notice and self.connection.is_connected() and (
+                self.clock() - self.last_state_save >= 60):
+            # Tells us after a crash when we were last online.
+            self.state['last_seen'] = self.clock()
+            self.save_state()
         if self.connection.is_connected():
-            pass
+            self.send_pending_whos()
+            if self.nickpass is not None and self.nickserv.poll():
+                self.debug_out(_('Identifying with NickServ again.'))
+                self.connection.privmsg('NickServ', 'identify ' + self.nickpass)
 
     def check_daily_jobs(self):
-        current_time = time.strftime('%a')
+        current_time = time.strftime('%a', time.localtime(self.clock()))
         if hasattr(self, 'daily_jobs_last_time') and current_time != self.daily_jobs_last_time:
             self.daily_jobs()
         self.daily_jobs_last_time = current_time
 
     def check_polling_jobs(self):
-        current_time = time.time()
+        current_time = self.clock()
         if hasattr(self, 'polling_jobs_last_time') and current_time != self.polling_jobs_last_time:
             self.polling_jobs()
         self.polling_jobs_last_time = current_time
 
     def on_currenttopic(self, c, e):
-        if e.arguments[0] == self.initial_channels[0]:
-            self.current_topic = e.arguments[1]
+        self.update_topic(e.arguments[0], e.arguments[1])
     def on_topic(self, c, e):
-        if e.target == self.initial_channels[0]:
-            self.current_topic = e.arguments[0]
+        self.update_topic(e.target, e.arguments[0])
+        self.confirm_word_of_the_day(e.target, e.arguments[0])
+
+    def update_topic(self, channel, topic):
+        if irc_lower(channel) in [irc_lower(c) for c in self.topic_channels]:
+            self.current_topics[irc_lower(channel)] = topic
 
     def run_forever(self):
         """In order to support custom timers, we can't call
         self.start()."""
+        sd_notify('READY=1')
+        if watchdog_timeout is not None:
+            threading.Thread(target=self.watchdog, daemon=True).start()
+        # The first connection attempt in the constructor may have
+        # failed.  Later failures schedule a reconnect by themselves.
+        if not self.connection.is_connected():
+            self.recon.run(self)
         while True:
+            self.mark_progress()
             self.check_timers()
+            self.finish_scripts()
+            self.post_url_titles()
+            self.logger.flush()
             self.ircobj.process_once(0.2)
             self.check_daily_jobs()
             self.check_polling_jobs()
 
+    def mark_progress(self):
+        now = self.clock()
+        self.last_progress = now
+        if self.last_sd_notify is None or now - self.last_sd_notify >= 10:
+            sd_notify('WATCHDOG=1')
+            self.last_sd_notify = now
+
+    def watchdog(self):
+        """Runs in its own thread and ends the process if the main loop
+        hangs."""
+        while True:
+            time.sleep(watchdog_timeout / 4)
+            stalled = self.clock() - self.last_progress
+            if stalled > watchdog_timeout:
+                sys.stderr.write(self.watchdog_dump(stalled))
+                sys.stderr.flush()
+                os._exit(watchdog_exit_code)
+
+    def watchdog_dump(self, stalled):
+        now = self.clock()
+        lines = [_('Watchdog: main loop stalled for %d seconds.') % stalled,
+                 _('Pending timers: %d') % len(self._timers)]
+        if self.last_message_time is not None:
+            lines.append(_('Last message: %d seconds ago')
+                         % (now - self.last_message_time))
+        for job in list(self.running_scripts):
+            lines.append(_('Running script: %s for %d seconds')
+                         % (job.path, now - job.start_time))
+        if self.queued_scripts:
+            lines.append(_('Queued scripts: %d') % len(self.queued_scripts))
+        return ''.join(line + '\n' for line in lines)
+
+def handle_sigterm(signum, frame):
+    # Shut down in the same way as on Ctrl-C.  A second SIGTERM
+    # terminates the bot immediately.
+    signal.signal(signal.SIGTERM, signal.SIG_DFL)
+    raise KeyboardInterrupt()
+
+def run_script_command(argv):
+    """Runs a script once as the bot would and prints the result,
+    without connecting to IRC."""
+    parser = argparse.ArgumentParser(prog='bot.py run-script')
+    parser.add_argument('--sender', default='user')
+    parser.add_argument('--receiver',
+                        help=_('channel or nick, defaults to the sender'))
+    parser.add_argument('--bot-nick', default='bot')
+    parser.add_argument('command')
+    # Everything after the command is the argument, even if it starts
+    # with "-", e.g. "calc -1".
+    parser.add_argument('arguments', nargs=argparse.REMAINDER)
+    args = parser.parse_args(argv)
+    path = find_script(unicodedata.normalize('NFC', args.command))
+    if path is None:
+        print(_('Error: Unknown command: %s') % args.command)
+        return 2
+    receiver = args.receiver if args.receiver is not None else args.sender
+    start_time = time.time()
+    output, errors, success = call_script(
+        path, ' '.join(args.arguments),
+        (unicodedata.normalize('NFC', args.sender), receiver), args.bot_nick)
+    print('script:   %s' % path)
+    print('result:   %s' % ('ok' if success else 'failed'))
+    print('duration: %.2fs' % (time.time() - start_time))
+    print('stdout:\n%s' % output.rstrip('\n'))
+    print('stderr:\n%s' % errors.rstrip('\n'))
+    return 0 if success else 1
+
 def setup_gettext():
     gettext.bindtextdomain('japanese_tools', '../gettext/locale')
     gettext.textdomain('japanese_tools')
@@ -323,27 +2243,52 @@ def main():
 
     setup_gettext()
 
-    if len(sys.argv) != 4 and len(sys.argv) != 5:
-        print(_('Usage: bot.py <server[:port]> <channel[,channel...]> <nickname> [NickServ password]'))
+    if sys.argv[1:2] == ['run-script']:
+        sys.exit(run_script_command(sys.argv[2:]))
+
+    parser = argparse.ArgumentParser(prog='bot.py')
+    parser.add_argument('server', metavar='[ircs://]server[:port]')
+    parser.add_argument('channels', metavar='channel[:key][,channel[:key]...]')
+    parser.add_argument('nickname')
+    parser.add_argument('nickpass', metavar='NickServ_password', nargs='?')
+    parser.add_argument('--topic-channel', action='append',
+                        help=_('channel with the word of the day in its topic, '
+                               'may be given several times'))
+    parser.add_argument('--audit-target', metavar='nick-or-channel',
+                        help=_('where to report admin commands'))
+    parser.add_argument('--tls', action='store_true',
+                        help=_('connect with TLS, implied by ircs:// and '
+                               'port 6697'))
+    parser.add_argument('--tls-insecure', action='store_true',
+                        help=_('use TLS without checking the certificate'))
+    args = parser.parse_args()
+
+    try:
+        address = parse_server_address(args.server,
+                                       args.tls or args.tls_insecure)
+    except ValueError:
+        print(_('Error: Invalid port.'))
+        sys.exit(1)
+    channels, channel_keys, invalid = parse_channels(args.channels)
+    if invalid:
+        print(_('Error: Invalid channels: %s')
+              % ', '.join('"%s"' % c for c in invalid))
+        sys.exit(1)
+    topic_channels = args.topic_channel
+    if topic_channels is None:
+        print(_('Warning: No --topic-channel given, using %s.') % channels[0])
+        topic_channels = channels[:1]
+    unknown = [c for c in topic_channels
+               if irc_lower(c) not in [irc_lower(d) for d in channels]]
+    if unknown:
+        print(_('Error: Topic channels must be joined: %s') % ', '.join(unknown))
         sys.exit(1)
 
-    s = sys.argv[1].split(':', 1)
-    server = s[0]
-    if len(s) == 2:
-        try:
-            port = int(s[1])
-        except ValueError:
-            print(_('Error: Invalid port.'))
-            sys.exit(1)
-    else:
-        port = 6667
-    channels = sys.argv[2].split(",")
-    nickname = sys.argv[3]
-    nickpass = None
-    if len(sys.argv) == 5:
-        nickpass = sys.argv[4]
-
-    bot = SimpleBot(channels, nickname, nickpass, server, port)
+    bot = SimpleBot(channels, args.nickname, args.nickpass, address.host,
+                    address.port, channel_keys, topic_channels,
+                    args.audit_target, tls=address.tls,
+                    tls_verify=not args.tls_insecure)
+    signal.signal(signal.SIGTERM, handle_sigterm)
     try:
         bot.run_forever()
     except KeyboardInterrupt:
@@ -0,0 +1,579 @@
+#!/usr/bin/python3
+# -*- coding: utf-8 -*-
+# Copyright: Christoph Dittmann <github@christoph-d.de>
+# License: GNU GPL, version 3 or later; http://www.gnu.org/copyleft/gpl.html
+#
+# Tests for the helper functions in bot.py.  Run them in this
+# directory with: python3 -m unittest test_bot
+
+import datetime
+import io
+import json
+import random
+import sys
+import tempfile
+import time
+import types
+import unittest
+import urllib.request
+
+# The tests don't connect anywhere, so they work without the irc
+# library.
+try:
+    import irc.bot
+except ImportError:
+    class Stub(object):
+        def __init__(self, *args, **kwargs):
+            pass
+    modules = {
+        'irc': {},
+        'irc.bot': { 'SingleServerIRCBot': Stub,
+                     'ExponentialBackoff': Stub },
+        'irc.connection': { 'Factory': Stub },
+        'irc.client': { 'ip_numstr_to_quad': None,
+                        'ip_quad_to_numstr': None },
+        'irc.modes': { 'parse_user_modes': None,
+                       'parse_channel_modes': None },
+        }
+    for name, attributes in modules.items():
+        module = types.ModuleType(name)
+        module.__dict__.update(attributes)
+        sys.modules[name] = module
+
+import bot
+
+class ComputeNewTopicTest(unittest.TestCase):
+    marker = 'Wort des Tages: '
+
+    def test_replace(self):
+        cases = [
+            # (old topic, new word, expected topic, expected old word)
+            ('Wort des Tages: 猫', '犬', 'Wort des Tages: 犬', '猫'),
+            ('Hallo | Wort des Tages: 猫 | mehr', '犬',
+             'Hallo | Wort des Tages: 犬 | mehr', '猫'),
+            ('Wort des Tages: ', '犬', 'Wort des Tages: 犬', ''),
+            ('Wort des Tages: 猫', '猫', 'Wort des Tages: 猫', '猫'),
+            ]
+        for topic, new_word, expected_topic, expected_word in cases:
+            with self.subTest(topic=topic):
+                self.assertEqual(
+                    bot.compute_new_topic(topic, self.marker, new_word),
+                    (expected_topic, expected_word))
+
+    def test_no_marker(self):
+        self.assertIsNone(bot.split_topic('Hallo', self.marker))
+
+class FakeClock(object):
+    def __init__(self):
+        self.now = 1000.0
+
+    def __call__(self):
+        return self.now
+
+class LoggerTest(unittest.TestCase):
+    def setUp(self):
+        self.clock = FakeClock()
+        self.out = io.StringIO()
+        self.logger = bot.Logger(clock=self.clock, out=self.out)
+
+    def lines(self):
+        return self.out.getvalue().splitlines()
+
+    def test_repetitions_are_counted(self):
+        self.logger.log('error 1')
+        self.logger.log('error 2')
+        self.logger.log('error 3')
+        self.assertEqual(self.lines(), ['error 1'])
+        self.clock.now += bot.Logger.repeat_window
+        self.logger.flush()
+        self.assertEqual(self.lines(),
+                         ['error 1', 'Repeated 2 times: error 1'])
+
+    def test_other_messages_are_printed(self):
+        self.logger.log('a')
+        self.logger.log('b')
+        self.assertEqual(self.lines(), ['a', 'b'])
+
+    def test_repetition_after_window(self):
+        self.logger.log('a')
+        self.clock.now += bot.Logger.repeat_window
+        self.logger.log('a')
+        self.assertEqual(self.lines(), ['a', 'a'])
+
+class LatencyStatsTest(unittest.TestCase):
+    def test_summary(self):
+        stats = bot.LatencyStats()
+        for seconds in range(1, 101):
+            stats.record('key', seconds)
+        self.assertEqual(stats.summary('key'), (100, 50, 95, 100))
+
+    def test_ring_buffer(self):
+        stats = bot.LatencyStats()
+        for seconds in range(bot.LatencyStats.max_samples + 10):
+            stats.record('key', seconds)
+        count, p50, p95, maximum = stats.summary('key')
+        self.assertEqual(count, bot.LatencyStats.max_samples)
+        self.assertEqual(maximum, bot.LatencyStats.max_samples + 9)
+
+    def test_least_recently_used_keys_are_dropped(self):
+        stats = bot.LatencyStats()
+        for key in range(bot.LatencyStats.max_keys):
+            stats.record(key, 1)
+        stats.record(0, 1)
+        stats.record('new', 1)
+        self.assertNotIn(1, stats.keys())
+        self.assertIn(0, stats.keys())
+        self.assertEqual(len(stats.keys()), bot.LatencyStats.max_keys)
+
+class ParseChannelsTest(unittest.TestCase):
+    def test_parse(self):
+        cases = [
+            ('#a', (['#a'], {}, [])),
+            ('#a,#b', (['#a', '#b'], {}, [])),
+            ('#a:key,&b', (['#a', '&b'], { '#a': 'key' }, [])),
+            ('#日本語', (['#日本語'], {}, [])),
+            ('a,#b', (['#b'], {}, ['a'])),
+            ('#a:,#', ([], {}, ['#a:', '#'])),
+            ('#a b', ([], {}, ['#a b'])),
+            ('#' + 'x' * 50, ([], {}, ['#' + 'x' * 50])),
+            # The length limit is in bytes.
+            ('#' + 'あ' * 17, ([], {}, ['#' + 'あ' * 17])),
+            ]
+        for arg, expected in cases:
+            with self.subTest(arg=arg):
+                self.assertEqual(bot.parse_channels(arg), expected)
+
+class PickWordOfTheDayTest(unittest.TestCase):
+    today = datetime.date(2021, 6, 1)
+
+    def entry(self, word, category='default', days_ago=None):
+        last_used = None
+        if days_ago is not None:
+            last_used = (self.today
+                         - datetime.timedelta(days=days_ago)).isoformat()
+        return { 'word': word, 'category': category, 'last_used': last_used }
+
+    def pick(self, pool, weights={}):
+        return bot.pick_word_of_the_day(pool, weights, self.today,
+                                        random.Random(1))
+
+    def test_least_recently_used(self):
+        pool = [self.entry('a', days_ago=100), self.entry('b', days_ago=200),
+                self.entry('c', days_ago=1)]
+        self.assertEqual(self.pick(pool)['word'], 'b')
+
+    def test_unused_words_first(self):
+        pool = [self.entry('a', days_ago=100), self.entry('b')]
+        self.assertEqual(self.pick(pool)['word'], 'b')
+
+    def test_recent_words_are_skipped(self):
+        pool = [self.entry('a', days_ago=1), self.entry('# comment')]
+        self.assertIsNone(self.pick(pool))
+
+    def test_missing_fields(self):
+        pool = [{ 'word': 'a' }, self.entry('b', days_ago=100)]
+        self.assertEqual(self.pick(pool)['word'], 'a')
+
+    def test_weights(self):
+        pool = [self.entry('a', 'x'), self.entry('b', 'y')]
+        self.assertEqual(self.pick(pool, { 'x': 0 })['word'], 'b')
+        self.assertIsNone(self.pick(pool, { 'x': 0, 'y': 0 }))
+
+class LoadWordPoolTest(unittest.TestCase):
+    def load(self, text):
+        with tempfile.NamedTemporaryFile('w', suffix='.json') as f:
+            f.write(text)
+            f.flush()
+            return bot.load_word_pool(f.name)
+
+    def test_broken_entries_are_skipped(self):
+        pool = self.load('[{"word": "a"}, {"category": "x"}, 5, '
+                         '{"word": "b", "last_used": 3}]')
+        self.assertEqual(pool, [{ 'word': 'a' }])
+
+    def test_broken_file(self):
+        for text in ['{"word": "a"}', '[']:
+            with self.subTest(text=text):
+                self.assertRaises(ValueError, self.load, text)
+
+    def test_missing_file(self):
+        self.assertEqual(bot.load_word_pool('/nonexistent/pool.json'), [])
+
+class NickServIdentifierTest(unittest.TestCase):
+    def setUp(self):
+        self.clock = FakeClock()
+        self.nickserv = bot.NickServIdentifier(self.clock)
+
+    def identify_times(self, seconds):
+        """Polls once per second, returns when poll() said yes."""
+        times = []
+        for t in range(seconds):
+            if self.nickserv.poll():
+                times.append(t)
+            self.clock.now += 1
+        return times
+
+    def test_patterns(self):
+        cases = [
+            ('This nickname is registered. Please choose a different '
+             'nickname, or identify via /msg NickServ identify <password>.',
+             True),
+            ('You are now identified for bot.', False),
+            ('Password accepted.', False),
+            ]
+        for text, pending in cases:
+            with self.subTest(text=text):
+                self.nickserv.pending = False
+                self.nickserv.handle_notice(text)
+                self.assertEqual(self.nickserv.pending, pending)
+
+    def test_success_clears_pending(self):
+        self.nickserv.identification_lost()
+        self.nickserv.handle_notice('You are now identified for bot.')
+        self.assertEqual(self.identify_times(100), [])
+
+    def test_wait_after_connect(self):
+        self.nickserv.handle_notice('This nickname is registered.')
+        self.assertEqual(self.identify_times(31), [30])
+
+    def test_backoff_and_cap(self):
+        self.clock.now += bot.NickServIdentifier.initial_delay
+        self.nickserv.identification_lost()
+        self.assertEqual(self.identify_times(2000),
+                         [0, 30, 90, 210, 450])
+
+    def test_identified_resets_backoff(self):
+        self.clock.now += bot.NickServIdentifier.initial_delay
+        self.nickserv.identification_lost()
+        self.assertEqual(self.identify_times(100), [0, 30, 90])
+        self.nickserv.identified()
+        self.nickserv.identification_lost()
+        self.assertEqual(self.identify_times(31), [0, 30])
+
+class RedactLineTest(unittest.TestCase):
+    def test_redact(self):
+        cases = [
+            ('PRIVMSG NickServ :identify secret',
+             'PRIVMSG NickServ :identify ***'),
+            (':bot!u@h PRIVMSG nickserv :IDENTIFY nick secret',
+             ':bot!u@h PRIVMSG nickserv :IDENTIFY ***'),
+            ('PASS secret', 'PASS ***'),
+            ('OPER admin secret', 'OPER admin ***'),
+            ('PRIVMSG #c :magic123 die', 'PRIVMSG #c :*** die'),
+            ('PRIVMSG #c :hello', 'PRIVMSG #c :hello'),
+            ]
+        for line, expected in cases:
+            with self.subTest(line=line):
+                self.assertEqual(bot.redact_line(line, ['magic123', '']),
+                                 expected)
+
+class TrafficRecorderTest(unittest.TestCase):
+    def setUp(self):
+        self.clock = FakeClock()
+        self.recorder = bot.TrafficRecorder(self.clock, lambda: ['secret'])
+
+    def test_wraparound(self):
+        total = bot.TrafficRecorder.max_lines + 10
+        for i in range(total):
+            self.clock.now += 1
+            self.recorder.record('<', 'PING %d' % i)
+        lines = self.recorder.format(total).splitlines()
+        self.assertEqual(len(lines), bot.TrafficRecorder.max_lines)
+        self.assertTrue(lines[0].endswith(' < PING 10'))
+        self.assertTrue(lines[-1].endswith(' < PING %d' % (total - 1)))
+
+    def test_format(self):
+        self.recorder.record('>', 'PRIVMSG NickServ :identify secret')
+        self.recorder.record('<', 'PING x')
+        t = time.strftime('%Y-%m-%d %H:%M:%S', time.localtime(self.clock.now))
+        self.assertEqual(self.recorder.format(5),
+                         '%s > PRIVMSG NickServ :identify ***\n'
+                         '%s < PING x\n' % (t, t))
+        self.assertEqual(self.recorder.format(1), '%s < PING x\n' % t)
+        self.assertEqual(self.recorder.format(0), '')
+
+class ParseDurationTest(unittest.TestCase):
+    def test_parse(self):
+        cases = [
+            ('30s', 30),
+            ('25m', 25 * 60),
+            ('1h30m', 90 * 60),
+            ('2時間', 2 * 60 * 60),
+            ('10分', 10 * 60),
+            ('1d12h', 36 * 60 * 60),
+            ('3日', 3 * 24 * 60 * 60),
+            ('', None),
+            ('m', None),
+            ('10', None),
+            ('10x', None),
+            ('1h 30m', None),
+            ('-5m', None),
+            ]
+        for text, expected in cases:
+            with self.subTest(text=text):
+                self.assertEqual(bot.parse_duration(text), expected)
+
+class UrlAllowedTest(unittest.TestCase):
+    def setUp(self):
+        self.allowed = bot.url_title_allowed_domains
+        self.denied = bot.url_title_denied_domains
+
+    def tearDown(self):
+        bot.url_title_allowed_domains = self.allowed
+        bot.url_title_denied_domains = self.denied
+
+    def test_scheme(self):
+        cases = [
+            ('http://example.org/', True),
+            ('https://example.org/a?b#c', True),
+            ('ftp://example.org/', False),
+            ('file:///etc/passwd', False),
+            ('http://', False),
+            ('http://example.org:x/', False),
+            ]
+        for url, expected in cases:
+            with self.subTest(url=url):
+                self.assertEqual(bot.url_allowed(url), expected)
+
+    def test_domains(self):
+        bot.url_title_allowed_domains = ['example.org']
+        bot.url_title_denied_domains = ['bad.example.org']
+        cases = [
+            ('https://example.org/', True),
+            ('https://www.Example.org/', True),
+            ('https://bad.example.org/', False),
+            ('https://x.bad.example.org/', False),
+            ('https://notexample.org/', False),
+            ('https://example.org.evil.com/', False),
+            ]
+        for url, expected in cases:
+            with self.subTest(url=url):
+                self.assertEqual(bot.url_allowed(url), expected)
+
+    def test_redirect(self):
+        handler = bot.CheckedRedirectHandler()
+        request = urllib.request.Request('https://example.org/')
+        self.assertIsNone(handler.redirect_request(
+            request, None, 302, 'Found', {}, 'file:///etc/passwd'))
+        self.assertIsNotNone(handler.redirect_request(
+            request, None, 302, 'Found', {}, 'https://example.org/b'))
+
+class GlobalAddressTest(unittest.TestCase):
+    def test_private_addresses(self):
+        for host in ['127.0.0.1', '10.1.2.3', '192.168.0.1', '169.254.1.1',
+                     '::1', 'fe80::1', '0.0.0.0', 'localhost']:
+            with self.subTest(host=host):
+                self.assertIsNone(bot.global_address(host, 80))
+
+class ExtractTitleTest(unittest.TestCase):
+    def test_extract(self):
+        cases = [
+            (b'<html><title>Hello</title></html>', None, 'Hello'),
+            (b'<TITLE lang="en">\n  Hello\n  world </TITLE>', None,
+             'Hello world'),
+            (b'<title>a &amp; b &#x65E5;&#26412;</title>', None, 'a & b 日本'),
+            ('<title>日本語</title>'.encode('utf-8'), None, '日本語'),
+            ('<title>日本語</title>'.encode('shift_jis'), 'shift_jis',
+             '日本語'),
+            ('<meta charset="euc-jp"><title>日本語</title>'.encode('euc_jp'),
+             None, '日本語'),
+            ('<meta http-equiv="Content-Type" content="text/html; '
+             'charset=Shift_JIS"><title>日本語</title>'.encode('shift_jis'),
+             None, '日本語'),
+            (b'<title>x</title>', 'no-such-charset', 'x'),
+            (b'<html>no title</html>', None, None),
+            (b'<title> </title>', None, None),
+            ]
+        for data, charset, expected in cases:
+            with self.subTest(data=data):
+                self.assertEqual(bot.extract_title(data, charset), expected)
+
+    def test_truncation(self):
+        title = bot.extract_title(('<title>%s</title>' % ('あ' * 200))
+                                  .encode('utf-8'))
+        self.assertLessEqual(len(title.encode('utf-8')), 300)
+        self.assertTrue(title.endswith('あ...'))
+
+class IrcLowerTest(unittest.TestCase):
+    def test_lower(self):
+        cases = [
+            ('Nick', 'nick'),
+            ('[Nick]\\~', '{nick}|^'),
+            ('#Channel', '#channel'),
+            # Non-ASCII is left alone.
+            ('#日本語', '#日本語'),
+            ('Ärger', 'Ärger'),
+            ('ＮＩＣＫ', 'ＮＩＣＫ'),
+            ]
+        for name, expected in cases:
+            with self.subTest(name=name):
+                self.assertEqual(bot.irc_lower(name), expected)
+
+class IsValidChannelTest(unittest.TestCase):
+    def test_valid(self):
+        for name in ['#a', '&a', '#日本語', '#[チャンネル]',
+                     '#' + 'x' * 49, '#' + 'あ' * 16]:
+            with self.subTest(name=name):
+                self.assertTrue(bot.is_valid_channel(name))
+
+    def test_invalid(self):
+        for name in ['', '#', 'a', '日本語', '#a b', '#a,b', '#a:b',
+                     '#a\x07', '#' + 'x' * 50,
+                     # 1 + 17 * 3 bytes
+                     '#' + 'あ' * 17]:
+            with self.subTest(name=name):
+                self.assertFalse(bot.is_valid_channel(name))
+
+class MessageBudgetTest(unittest.TestCase):
+    def test_budget(self):
+        cases = [
+            # (line length, command, target, prefix length, expected)
+            (512, 'PRIVMSG', '#c', 50, 512 - 14 - 52),
+            (512, 'NOTICE', '#c', 50, 512 - 13 - 52),
+            (1024, 'PRIVMSG', '#c', 50, 1024 - 14 - 52),
+            # The target counts in bytes.
+            (512, 'PRIVMSG', '#日本語', 50, 512 - 22 - 52),
+            (512, 'PRIVMSG', '#' + 'x' * 49, 80, 512 - 62 - 82),
+            # Too little room left, send some text anyway.
+            (100, 'PRIVMSG', '#' + 'x' * 49, 80, bot.min_message_length),
+            ]
+        for line_length, command, target, prefix_length, expected in cases:
+            with self.subTest(line_length=line_length, target=target):
+                self.assertEqual(bot.message_budget(
+                    line_length, command, target, prefix_length), expected)
+
+class ParseTimerDirectiveTest(unittest.TestCase):
+    def test_valid(self):
+        cases = [
+            ('/timer 5 key', (5, 'key')),
+            ('/timer 0 key', (0, 'key')),
+            ('/timer %d k' % bot.max_timer_delay, (bot.max_timer_delay, 'k')),
+            ]
+        for line, expected in cases:
+            with self.subTest(line=line):
+                self.assertEqual(bot.parse_timer_directive(line), expected)
+
+    def test_invalid(self):
+        for line in ['/timer x key', '/timer 5', '/timer 5 ', '/timer -1 k',
+                     # The argument is a single word.
+                     '/timer 5 a b',
+                     '/timer %d k' % (bot.max_timer_delay + 1),
+                     '/timer 5 ' + 'k' * (bot.max_timer_argument_length + 1),
+                     # Fullwidth digits
+                     '/timer ５ k']:
+            with self.subTest(line=line):
+                self.assertRaises(ValueError, bot.parse_timer_directive, line)
+
+class ParseServerAddressTest(unittest.TestCase):
+    def test_parse(self):
+        cases = [
+            # (address, force_tls, (host, port, tls))
+            ('irc.example.org', False, ('irc.example.org', 6667, False)),
+            ('irc.example.org:6697', False, ('irc.example.org', 6697, True)),
+            ('irc.example.org:7000', False, ('irc.example.org', 7000, False)),
+            ('ircs://irc.example.org', False, ('irc.example.org', 6697, True)),
+            ('ircs://irc.example.org:7000/', False,
+             ('irc.example.org', 7000, True)),
+            ('irc://irc.example.org', False, ('irc.example.org', 6667, False)),
+            ('irc.example.org', True, ('irc.example.org', 6697, True)),
+            ('irc.example.org:6667', True, ('irc.example.org', 6667, True)),
+            ]
+        for address, force_tls, expected in cases:
+            with self.subTest(address=address, force_tls=force_tls):
+                self.assertEqual(
+                    tuple(bot.parse_server_address(address, force_tls)),
+                    expected)
+
+    def test_invalid_port(self):
+        for address in ['host:x', 'host:0', 'host:65536']:
+            with self.subTest(address=address):
+                self.assertRaises(ValueError, bot.parse_server_address,
+                                  address)
+
+class SplitMessageTest(unittest.TestCase):
+    def test_split(self):
+        cases = [
+            # (text, max_bytes, max_messages, expected)
+            ('short', 10, None, ['short']),
+            ('hello world foo bar', 11, None, ['hello world', 'foo bar']),
+            ('one\ntwo', 10, None, ['one', 'two']),
+            # Multi-byte characters are never cut in half.
+            ('あいうえおかきくけこ', 10, None,
+             ['あいう', 'えおか', 'きくけ', 'こ']),
+            # Ideographic spaces are break points too.
+            ('日本語　テキスト です', 12, None, ['日本語', 'テキスト', 'です']),
+            # A word longer than a message is split anyway.
+            ('a ' + 'x' * 25 + ' b', 10, None,
+             ['a', 'x' * 10, 'x' * 10, 'xxxxx b']),
+            ('a\nb\nc\nd', 10, 3, ['a', 'b', 'c […]']),
+            ('aaaa bbbb cccc dddd eeee', 9, 2, ['aaaa bbbb', 'ccc […]']),
+            ('a\n\nb', 10, None, ['a', '', 'b']),
+            # Not even one character fits, send one per message.
+            ('abc', 0, None, ['a', 'b', 'c']),
+            ('あい', 2, None, ['あ', 'い']),
+            ('ab', -5, None, ['a', 'b']),
+            ]
+        for text, max_bytes, max_messages, expected in cases:
+            with self.subTest(text=text):
+                self.assertEqual(
+                    bot.split_message(text, max_bytes, max_messages),
+                    expected)
+
+    def test_messages_fit(self):
+        for text in ['あ' * 300, 'ab ' * 200, 'x' * 1000]:
+            with self.subTest(text=text[:10]):
+                for message in bot.split_message(text, 50, 6):
+                    self.assertLessEqual(len(message.encode('utf-8')), 50)
+
+class StateFromJsonTest(unittest.TestCase):
+    def test_wrong_types(self):
+        cases = [
+            ({}, {}),
+            ({ 'running': True, 'last_seen': 5.5 },
+             { 'running': True, 'last_seen': 5.5 }),
+            ({ 'last_seen': 'x' }, {}),
+            ({ 'offline_notices': { '#a': 1, '#b': 'x' } },
+             { 'offline_notices': { '#a': 1 } }),
+            ({ 'offline_notices': [] }, { 'offline_notices': {} }),
+            ({ 'ignored_nicks': ['a', 5] }, { 'ignored_nicks': ['a'] }),
+            ({ 'ignored_nicks': 5 }, { 'ignored_nicks': [] }),
+            ]
+        for state, expected in cases:
+            with self.subTest(state=state):
+                self.assertEqual(bot.state_from_json(state), expected)
+
+    def test_not_an_object(self):
+        for state in [[], 'x', None]:
+            with self.subTest(state=state):
+                self.assertRaises(ValueError, bot.state_from_json, state)
+
+class TimersFromJsonTest(unittest.TestCase):
+    script = bot.scripts[0][1]
+
+    def test_round_trip(self):
+        timers = [(1000.5, self.script, 'arg', ('nick', '#c'), '#c'),
+                  (2000.0, None, 'tea', ('nick', 'nick'), 'nick')]
+        saved = json.loads(json.dumps(
+            [[t[0], t[1], t[2], list(t[3]), t[4]] for t in timers]))
+        self.assertEqual(bot.timers_from_json(saved), timers)
+
+    def test_broken_entries(self):
+        saved = [[1, self.script, 'a', ['n', '#c'], '#c'],
+                 ['x', self.script, 'a', ['n', '#c'], '#c'],
+                 [1, '../missing.sh', 'a', ['n', '#c'], '#c'],
+                 [1, self.script, 'a', 'n', '#c'],
+                 [1, self.script],
+                 5]
+        self.assertEqual(bot.timers_from_json(saved),
+                         [(1.0, self.script, 'a', ('n', '#c'), '#c')])
+        self.assertEqual(bot.timers_from_json({ 'x': 1 }), [])
+
+    def test_timer_targets(self):
+        saved = [[self.script, '#c'], ['../missing.sh', '#c'],
+                 [self.script], [self.script, 5], 'x']
+        self.assertEqual(bot.timer_targets_from_json(saved),
+                         [[self.script, '#c']])
+        self.assertEqual(bot.timer_targets_from_json('x'), [])
+
+if __name__ == '__main__':
+    unittest.main()